@@ -1,20 +1,33 @@
-#![feature(async_closure)]
 use anyhow::{self, bail};
 use async_std::io::prelude::*;
 use async_std::{fs, io};
 use colored::Colorize;
 use digest::Digest;
+use entropic_object_store::diff;
 use entropic_object_store::objects::event::{ EventBuilder, Claim };
 use entropic_object_store::envelope::Envelope;
+use entropic_object_store::objects::version::Version;
+use entropic_object_store::stores::extracted::ExtractedBlobs;
 use entropic_object_store::stores::loose::LooseStore;
+use entropic_object_store::stores::multiple::CachingStore;
 use entropic_object_store::stores::packed::PackedStore;
+use entropic_object_store::stores::remote::RemoteStore;
 use entropic_object_store::stores::{ReadableStore, WritableStore};
 use entropic_object_store::keys::{ load_public_key, load_secret_key };
+use entropic_object_store::package::{self, PackageState};
+use entropic_object_store::resolve;
+use entropic_object_store::snapshot;
+use entropic_object_store::spec;
+use entropic_object_store::sync;
+use entropic_object_store::tarball;
+use entropic_object_store::verify;
+use entropic_object_store::http::server;
 use futures::future::FutureExt;
 use futures::future::{join_all, select_all};
 use sha2::Sha256;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 use structopt::StructOpt;
 
 enum Backends {
@@ -60,6 +73,8 @@ enum Command {
         hashes: Vec<String>,
         #[structopt(short, long, default_value = "loose")]
         backend: Backends,
+        #[structopt(short, long)]
+        remote: Option<String>,
     },
     GetAll {
         hashfile: PathBuf,
@@ -67,9 +82,65 @@ enum Command {
         backend: Backends,
     },
     Pack {},
+    Import {
+        #[structopt(parse(from_os_str))]
+        tarball: PathBuf,
+    },
+    Unpack {
+        version: String,
+        #[structopt(parse(from_os_str))]
+        destination: PathBuf,
+        #[structopt(long)]
+        copy: bool,
+    },
+    State {
+        head: String,
+    },
+    Audit {
+        heads: Vec<String>,
+    },
+    Resolve {
+        spec: String,
+        #[structopt(short, long)]
+        package: Vec<String>,
+    },
+    Verify {
+        version: String,
+        #[structopt(parse(from_os_str))]
+        destination: PathBuf,
+        #[structopt(long)]
+        repair: bool,
+    },
+    Export {
+        version: String,
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    Diff {
+        a: String,
+        b: String,
+        #[structopt(short, long)]
+        patch: bool,
+    },
+    Send {
+        heads: Vec<String>,
+    },
+    Receive {
+        heads: Vec<String>,
+    },
+    Serve {
+        #[structopt(short, long, default_value = "127.0.0.1:8080")]
+        listen: String,
+    },
     Snapshot {
         #[structopt(short, long)]
         comment: Option<String>,
+        #[structopt(long, parse(from_os_str))]
+        package: Option<PathBuf>,
+        // Who is signing, as "Name <email>". Founding a package also names
+        // them as its first authority.
+        #[structopt(long)]
+        signatory: String,
         parent: Option<String>
     }
 }
@@ -212,6 +283,20 @@ async fn cmd_get<S: ReadableStore, T: AsRef<str>>(eos: &Eos, store: S, hashes: &
     Ok(())
 }
 
+fn parse_ids<T: AsRef<str>>(hashes: &[T]) -> anyhow::Result<Vec<[u8; 32]>> {
+    let mut ids = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        let decoded = hex::decode(hash.as_ref())?;
+        if decoded.len() != 32 {
+            bail!("Please pass 64-character hex ids (saw \"{}\")", hash.as_ref());
+        }
+        let mut id = [0u8; 32];
+        id.copy_from_slice(&decoded[..]);
+        ids.push(id);
+    }
+    Ok(ids)
+}
+
 #[async_std::main]
 async fn main() -> anyhow::Result<()> {
     let eos = Eos::from_args();
@@ -222,6 +307,7 @@ async fn main() -> anyhow::Result<()> {
     });
 
     let packfiles = PackedStore::<Sha256>::load_all(&destination)?;
+    let blobs = ExtractedBlobs::<Sha256>::new(destination.join("extracted"));
     let loose = LooseStore::<Sha256>::new(destination);
 
     match &eos.command {
@@ -240,9 +326,13 @@ async fn main() -> anyhow::Result<()> {
             }
             cmd_add(&eos, loose, &processed_files).await?
         }
-        Command::Get { hashes, backend } => match backend {
-            Backends::Loose => cmd_get(&eos, loose, &hashes[..]).await?,
-            Backends::Packed => cmd_get(&eos, packfiles, &hashes[..]).await?,
+        Command::Get { hashes, backend, remote } => match (backend, remote) {
+            (_, Some(remote)) => {
+                let store = CachingStore::new(loose, RemoteStore::<Sha256>::new(remote)?);
+                cmd_get(&eos, store, &hashes[..]).await?
+            }
+            (Backends::Loose, None) => cmd_get(&eos, loose, &hashes[..]).await?,
+            (Backends::Packed, None) => cmd_get(&eos, packfiles, &hashes[..]).await?,
         },
         Command::GetAll { hashfile, backend } => {
             let data = fs::read(&hashfile).await?;
@@ -253,7 +343,191 @@ async fn main() -> anyhow::Result<()> {
             }
         }
         Command::Pack {} => loose.to_packed_store().await?,
-        Command::Snapshot { comment, parent } => {
+        Command::Import { tarball } => {
+            let file = std::fs::File::open(tarball)?;
+            let id = tarball::import(&loose, std::io::BufReader::new(file)).await?;
+            println!("{}", hex::encode(id));
+        }
+        Command::Unpack { version, destination, copy } => {
+            let id = parse_ids(&[version])?[0];
+            let store = (loose, packfiles);
+            let version = match store.get(id).await? {
+                Some(envelope) => Version::from_envelope(&envelope)?,
+                None => bail!("could not find version {}", hex::encode(id)),
+            };
+            if *copy {
+                version.unpack(destination, &store).await?;
+            } else {
+                version.unpack_sync_linked(destination, &store, &blobs)?;
+            }
+        }
+        Command::State { head } => {
+            let head = parse_ids(&[head])?[0];
+            let store = (loose, packfiles);
+            let state = PackageState::load(&store, &head).await?;
+            for (name, key) in state.authorities() {
+                println!("authority {} {}", key, name);
+            }
+            for (version, id) in state.versions() {
+                match state.yank_reason(version) {
+                    Some(reason) => {
+                        println!("version {} {} yanked: {}", version, hex::encode(id), reason)
+                    }
+                    None => println!("version {} {}", version, hex::encode(id)),
+                }
+            }
+            for (tag, version) in state.tags() {
+                println!("tag {} {}", tag, version);
+            }
+        }
+        Command::Audit { heads } => {
+            let heads = parse_ids(&heads[..])?;
+            let store = (loose, packfiles);
+            let audit = package::audit(&store, &heads[..])?;
+            for (id, err) in audit.rejected.iter() {
+                println!("rejected {} {}", hex::encode(id), err);
+            }
+            for equivocation in audit.equivocations.iter() {
+                let subjects: Vec<_> =
+                    equivocation.subjects.iter().map(|subject| subject.to_string()).collect();
+                println!(
+                    "equivocation by {} ({}): {}",
+                    equivocation.signatory,
+                    equivocation.key,
+                    subjects.join(", ")
+                );
+                println!(
+                    "  {} {}",
+                    hex::encode(equivocation.first),
+                    hex::encode(&equivocation.first_signature)
+                );
+                println!(
+                    "  {} {}",
+                    hex::encode(equivocation.second),
+                    hex::encode(&equivocation.second_signature)
+                );
+            }
+            if !audit.rejected.is_empty() || !audit.equivocations.is_empty() {
+                bail!(
+                    "found {} rejected events and {} equivocations",
+                    audit.rejected.len(),
+                    audit.equivocations.len()
+                );
+            }
+        }
+        Command::Resolve { spec, package } => {
+            let store = (loose, packfiles);
+            let mut packages = std::collections::HashMap::new();
+            for entry in package {
+                match entry.rfind('=') {
+                    Some(idx) => {
+                        let head = parse_ids(&[&entry[idx + 1..]])?[0];
+                        let state = PackageState::load(&store, &head).await?;
+                        packages.insert(entry[..idx].to_string(), state);
+                    }
+                    None => bail!("expected --package <name>=<head>, got {:?}", entry),
+                }
+            }
+
+            let arg = spec::parse(spec)?;
+            let id = resolve::select(&packages, &arg)?;
+            let graph = resolve::resolve(&store, &packages, &id).await?;
+            for (version, dependencies) in graph.edges.iter() {
+                println!("{}", hex::encode(version));
+                for (name, dependency) in dependencies.iter() {
+                    println!("  {} {}", name, hex::encode(dependency));
+                }
+            }
+        }
+        Command::Verify { version, destination, repair } => {
+            let id = parse_ids(&[version])?[0];
+            let store = (loose, packfiles);
+            let version = match store.get(id).await? {
+                Some(envelope) => Version::from_envelope(&envelope)?,
+                None => bail!("could not find version {}", hex::encode(id)),
+            };
+
+            let report = verify::verify::<Sha256, _>(&version, destination)?;
+            for path in report.modified.iter() {
+                eos.log(format!("M {}", path))?;
+            }
+            for path in report.missing.iter() {
+                eos.log(format!("D {}", path))?;
+            }
+            for path in report.extra.iter() {
+                eos.log(format!("? {}", path))?;
+            }
+
+            if *repair {
+                verify::repair(&version, destination, &store, &report)?;
+            } else if !report.is_clean() {
+                bail!("{:?} does not match version {}", destination, hex::encode(id));
+            }
+        }
+        Command::Export { version, output } => {
+            let id = parse_ids(&[version])?[0];
+            let store = (loose, packfiles);
+            match output {
+                Some(path) => {
+                    let file = std::fs::File::create(path)?;
+                    tarball::export(&store, id, file).await?;
+                }
+                None => {
+                    tarball::export(&store, id, std::io::stdout()).await?;
+                }
+            }
+        }
+        Command::Diff { a, b, patch } => {
+            let ids = parse_ids(&[a, b])?;
+            let store = (loose, packfiles);
+            let mut versions = Vec::new();
+            for id in &ids {
+                match store.get(id).await? {
+                    Some(envelope) => versions.push(Version::from_envelope(&envelope)?),
+                    None => bail!("could not find version {}", hex::encode(id)),
+                }
+            }
+
+            for change in diff::diff(&versions[0], &versions[1]) {
+                println!("{}", change);
+                if *patch {
+                    if let Some(text) = diff::unified(&store, &change).await? {
+                        print!("{}", text);
+                    }
+                }
+            }
+        }
+        Command::Send { heads } => {
+            let heads = parse_ids(&heads[..])?;
+            let store = (loose, packfiles);
+            let sent = sync::send(&mut io::stdin(), &mut io::stdout(), &store, &heads[..]).await?;
+            eos.error(format!("sent {} objects", sent))?;
+        }
+        Command::Receive { heads } => {
+            let heads = parse_ids(&heads[..])?;
+            let store = (loose.clone(), packfiles);
+            let received = sync::receive(
+                &mut io::stdin(),
+                &mut io::stdout(),
+                &store,
+                &loose,
+                &heads[..],
+            )
+            .await?;
+            eos.error(format!("received {} objects", received))?;
+        }
+        Command::Serve { listen } => {
+            let listener = async_std::net::TcpListener::bind(listen).await?;
+            eos.log(format!("listening on {}", listener.local_addr()?))?;
+            let quiet = eos.quiet;
+            let report = move |e: anyhow::Error| {
+                if !quiet {
+                    eprintln!("{:#}", e);
+                }
+            };
+            server::serve(listener, Arc::new((loose, packfiles)), report).await?
+        }
+        Command::Snapshot { comment, package, signatory, parent } => {
             let mut base = dirs::home_dir().unwrap();
             base.push(".ssh");
             let mut secret_key_src = base.clone();
@@ -274,14 +548,36 @@ async fn main() -> anyhow::Result<()> {
                     data: comment_bytes
                 });
 
-            if let Some(p) = parent {
-                let decoded = hex::decode(p)?;
-                if decoded.len() != 32 {
-                    bail!("Please pass a 64-byte hex parent value");
+            if let Some(package) = package {
+                let version = snapshot::snapshot(&loose, package).await?;
+                let envelope = version.to_envelope()?;
+                let (id, _) = envelope.content_address::<Sha256>();
+                loose.add(envelope).await?;
+                ev = ev.claim(Claim::Publication {
+                    version: version.version().to_string(),
+                    id: id.to_vec(),
+                });
+            }
+
+            match parent {
+                Some(p) => {
+                    let decoded = hex::decode(p)?;
+                    if decoded.len() != 32 {
+                        bail!("Please pass a 64-byte hex parent value");
+                    }
+                    ev = ev.parent(decoded)
+                }
+                // The first event founds the package, so name its signer as
+                // an authority to let them sign the events that follow.
+                None => {
+                    ev = ev.claim(Claim::AuthorityAdd {
+                        public_key: hex::encode(pk),
+                        name: signatory.clone(),
+                    })
                 }
-                ev = ev.parent(decoded)
             }
-            let signed = ev.sign("Chris Dickinson <chris@neversaw.us>", &sk, &())?;
+            let store = (loose.clone(), packfiles);
+            let signed = ev.sign(signatory, &sk, &store)?;
             let mut buf = Vec::new();
             signed.to_bytes(&mut buf);
             let envelope = Envelope::Event(buf);
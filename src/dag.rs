@@ -0,0 +1,349 @@
+use crate::objects::event::{decode_head, Event};
+use crate::stores::ReadableStore;
+use chrono::prelude::*;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+// Operations over the event graph of a package, for working out what two
+// histories share and what one of them lacks.
+//
+// Merge bases and missing events are found by painting: both sides walk back
+// from their heads together, newest first, marking every event with the sides
+// that reach it. An event reached from both sides is common, and marks its own
+// ancestors stale. The walk stops as soon as only stale events are left to
+// visit, so two histories that share most of their past only load what they
+// do not share.
+//
+// Like any walk ordered by clocks, painting trusts `at`. An event dated before
+// one of its ancestors can make the walk stop before it has learned that an
+// event is common. `merge_base` checks its candidates against each other, so
+// its answer is exact; `missing` may then list events the other side already
+// has, but never leaves out one it lacks.
+const LEFT: u8 = 1;
+const RIGHT: u8 = 2;
+const STALE: u8 = 4;
+
+struct Painter<'a, S: ReadableStore> {
+    store: &'a S,
+    events: HashMap<[u8; 32], Event>,
+    flags: HashMap<[u8; 32], u8>,
+    queue: BinaryHeap<(DateTime<Utc>, [u8; 32])>,
+}
+
+impl<'a, S: ReadableStore + Sync> Painter<'a, S> {
+    fn new(store: &'a S) -> Self {
+        Painter {
+            store,
+            events: HashMap::new(),
+            flags: HashMap::new(),
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    async fn load(&mut self, id: &[u8; 32]) -> anyhow::Result<&Event> {
+        if !self.events.contains_key(id) {
+            let event = decode_head(id, self.store.get(id).await?)?;
+            self.events.insert(*id, event);
+        }
+        Ok(&self.events[id])
+    }
+
+    // Like `load`, but reports whether the event is in the store at all.
+    async fn try_load(&mut self, id: &[u8; 32]) -> anyhow::Result<bool> {
+        if !self.events.contains_key(id) {
+            match self.store.get(id).await? {
+                Some(envelope) => {
+                    let event = decode_head(id, Some(envelope))?;
+                    self.events.insert(*id, event);
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    // Adds `flags` to an event, queueing it again if they are news to it.
+    async fn mark(&mut self, id: &[u8; 32], flags: u8) -> anyhow::Result<()> {
+        let known = self.flags.get(id).copied().unwrap_or(0);
+        if known & flags == flags {
+            return Ok(());
+        }
+        let at = *self.load(id).await?.at();
+        self.flags.insert(*id, known | flags);
+        self.queue.push((at, *id));
+        Ok(())
+    }
+
+    fn converged(&self) -> bool {
+        self.queue.iter().all(|(_, id)| self.flags[id] & STALE != 0)
+    }
+
+    // Paints the histories behind `left` and `right`, returning the common
+    // events found before the walk converged.
+    async fn paint(
+        &mut self,
+        left: &[[u8; 32]],
+        right: &[[u8; 32]],
+    ) -> anyhow::Result<Vec<[u8; 32]>> {
+        for id in left {
+            self.mark(id, LEFT).await?;
+        }
+        for id in right {
+            self.mark(id, RIGHT).await?;
+        }
+
+        let mut common = Vec::new();
+        while !self.converged() {
+            let id = match self.queue.pop() {
+                Some((_, id)) => id,
+                None => break,
+            };
+            let mut flags = self.flags[&id];
+            if flags & (LEFT | RIGHT) == LEFT | RIGHT && flags & STALE == 0 {
+                common.push(id);
+                flags |= STALE;
+                self.flags.insert(id, flags);
+            }
+            let parents = self.events[&id].parents().to_vec();
+            for parent in parents.iter() {
+                self.mark(parent, flags).await?;
+            }
+        }
+        Ok(common)
+    }
+
+    // Follows parents from `descendant` until it finds `ancestor`. This does
+    // not trust clocks, so a negative answer walks the whole history.
+    async fn reaches(
+        &mut self,
+        descendant: &[u8; 32],
+        ancestor: &[u8; 32],
+    ) -> anyhow::Result<bool> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![*descendant];
+        while let Some(id) = pending.pop() {
+            if id == *ancestor {
+                return Ok(true);
+            }
+            if seen.insert(id) {
+                pending.extend(self.load(&id).await?.parents().iter().cloned());
+            }
+        }
+        Ok(false)
+    }
+}
+
+// Returns the best common ancestors of two heads: the events both histories
+// contain that are not ancestors of another such event. There is usually one,
+// but criss-cross merges can leave several; they are returned in id order.
+// A head that descends from the other has that other head as its merge base.
+pub async fn merge_base<S: ReadableStore + Sync>(
+    store: &S,
+    left: &[u8; 32],
+    right: &[u8; 32],
+) -> anyhow::Result<Vec<[u8; 32]>> {
+    let mut painter = Painter::new(store);
+    let common = painter.paint(&[*left], &[*right]).await?;
+
+    let mut bases = Vec::new();
+    for candidate in common.iter() {
+        let mut redundant = false;
+        for other in common.iter().filter(|other| *other != candidate) {
+            if painter.reaches(other, candidate).await? {
+                redundant = true;
+                break;
+            }
+        }
+        if !redundant {
+            bases.push(*candidate);
+        }
+    }
+    bases.sort();
+    Ok(bases)
+}
+
+// Returns the events reachable from `heads` but not from `known`, parents
+// first, so they can be sent in an order the receiver can check as they
+// arrive. Every event behind `heads` must be in `store`. A `known` head that
+// is not, say one a peer has written since, is skipped: whatever it shares
+// with `heads` is reached through the known heads that remain.
+pub async fn missing<S: ReadableStore + Sync>(
+    store: &S,
+    heads: &[[u8; 32]],
+    known: &[[u8; 32]],
+) -> anyhow::Result<Vec<([u8; 32], Event)>> {
+    let mut painter = Painter::new(store);
+    let mut present = Vec::with_capacity(known.len());
+    for id in known {
+        if painter.try_load(id).await? {
+            present.push(*id);
+        }
+    }
+    painter.paint(heads, &present).await?;
+
+    let wanted: HashSet<_> = painter
+        .flags
+        .iter()
+        .filter(|(_, flags)| *flags & RIGHT == 0)
+        .map(|(id, _)| *id)
+        .collect();
+    let mut roots: Vec<_> = wanted.iter().cloned().collect();
+    roots.sort_by_key(|id| (*painter.events[id].at(), *id));
+
+    let mut placed = HashSet::new();
+    let mut order = Vec::new();
+    for root in roots {
+        let mut stack = vec![(root, false)];
+        while let Some((id, expanded)) = stack.pop() {
+            if placed.contains(&id) {
+                continue;
+            }
+            if expanded {
+                placed.insert(id);
+                order.push(id);
+                continue;
+            }
+            stack.push((id, true));
+            for parent in painter.events[&id].parents().iter().rev() {
+                if wanted.contains(parent) && !placed.contains(parent) {
+                    stack.push((*parent, false));
+                }
+            }
+        }
+    }
+
+    Ok(order
+        .into_iter()
+        .map(|id| {
+            let event = painter
+                .events
+                .remove(&id)
+                .expect("painted events are loaded");
+            (id, event)
+        })
+        .collect())
+}
+
+// Whether `head` is `ancestor` or has it somewhere in its history.
+pub async fn descends_from<S: ReadableStore + Sync>(
+    store: &S,
+    head: &[u8; 32],
+    ancestor: &[u8; 32],
+) -> anyhow::Result<bool> {
+    Painter::new(store).reaches(head, ancestor).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::event::{Claim, EventBuilder};
+    use crate::testing::{add_event, authority, publication, scratch_store};
+    use sodiumoxide::crypto::sign;
+
+    fn ids(events: Vec<([u8; 32], Event)>) -> Vec<[u8; 32]> {
+        events.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[async_std::test]
+    async fn dag_operations_find_shared_and_missing_history() {
+        let store = scratch_store("dag");
+        let (pk, sk) = sign::gen_keypair();
+
+        // root - x - y - p, q, w
+        //          \ z - p, q
+        let root = EventBuilder::new().claim(authority("test", &pk));
+        let root = add_event(&store, &sk, 100, root).await;
+        let x = add_event(
+            &store,
+            &sk,
+            200,
+            EventBuilder::new()
+                .parent(root)
+                .claim(publication("1.0.0", [1; 32])),
+        )
+        .await;
+        let y = add_event(
+            &store,
+            &sk,
+            300,
+            EventBuilder::new()
+                .parent(x)
+                .claim(publication("1.1.0", [1; 32])),
+        )
+        .await;
+        let z = add_event(
+            &store,
+            &sk,
+            250,
+            EventBuilder::new()
+                .parent(x)
+                .claim(publication("2.0.0", [1; 32])),
+        )
+        .await;
+        let tag = |tag: &str| Claim::Tag {
+            tag: tag.to_string(),
+            version: "1.0.0".to_string(),
+        };
+        let p = add_event(
+            &store,
+            &sk,
+            400,
+            EventBuilder::new().parent(y).parent(z).claim(tag("a")),
+        )
+        .await;
+        let q = add_event(
+            &store,
+            &sk,
+            410,
+            EventBuilder::new().parent(y).parent(z).claim(tag("b")),
+        )
+        .await;
+        // Dated before all of its ancestors.
+        let w = add_event(
+            &store,
+            &sk,
+            50,
+            EventBuilder::new()
+                .parent(y)
+                .claim(publication("1.2.0", [1; 32])),
+        )
+        .await;
+
+        assert_eq!(merge_base(&store, &y, &z).await.unwrap(), vec![x]);
+        assert_eq!(merge_base(&store, &z, &y).await.unwrap(), vec![x]);
+        assert_eq!(merge_base(&store, &p, &y).await.unwrap(), vec![y]);
+        assert_eq!(merge_base(&store, &y, &y).await.unwrap(), vec![y]);
+        assert_eq!(merge_base(&store, &w, &z).await.unwrap(), vec![x]);
+        let mut criss_cross = vec![y, z];
+        criss_cross.sort();
+        assert_eq!(merge_base(&store, &p, &q).await.unwrap(), criss_cross);
+        assert!(merge_base(&store, &[9; 32], &y).await.is_err());
+
+        assert_eq!(ids(missing(&store, &[y], &[z]).await.unwrap()), vec![y]);
+        assert_eq!(
+            ids(missing(&store, &[p], &[root]).await.unwrap()),
+            vec![x, z, y, p]
+        );
+        assert_eq!(
+            ids(missing(&store, &[p, q], &[y]).await.unwrap()),
+            vec![z, p, q]
+        );
+        assert_eq!(ids(missing(&store, &[w], &[z]).await.unwrap()), vec![y, w]);
+        assert!(missing(&store, &[z], &[p]).await.unwrap().is_empty());
+        assert!(missing(&store, &[], &[p]).await.unwrap().is_empty());
+        assert_eq!(
+            ids(missing(&store, &[y], &[[9; 32], x]).await.unwrap()),
+            vec![y]
+        );
+        assert_eq!(
+            ids(missing(&store, &[x], &[[9; 32]]).await.unwrap()),
+            vec![root, x]
+        );
+        assert!(missing(&store, &[[9; 32]], &[x]).await.is_err());
+
+        assert!(descends_from(&store, &p, &root).await.unwrap());
+        assert!(descends_from(&store, &w, &x).await.unwrap());
+        assert!(descends_from(&store, &y, &y).await.unwrap());
+        assert!(!descends_from(&store, &z, &y).await.unwrap());
+        assert!(!descends_from(&store, &root, &p).await.unwrap());
+    }
+}
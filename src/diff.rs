@@ -0,0 +1,235 @@
+use crate::envelope::Envelope;
+use crate::objects::version::{Mode, Version};
+use crate::stores::ReadableStore;
+use anyhow::{self, bail};
+use similar::TextDiff;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    Added { path: String, mode: Mode, id: [u8; 32] },
+    Removed { path: String, mode: Mode, id: [u8; 32] },
+    Modified { path: String, from: (Mode, [u8; 32]), to: (Mode, [u8; 32]) },
+    Renamed { from: String, to: String, mode: Mode, id: [u8; 32] },
+}
+
+impl Change {
+    // The path a change is listed under: the new path for renames.
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added { path, .. } => path,
+            Change::Removed { path, .. } => path,
+            Change::Modified { path, .. } => path,
+            Change::Renamed { to, .. } => to,
+        }
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Change::Added { path, .. } => write!(f, "A {}", path),
+            Change::Removed { path, .. } => write!(f, "D {}", path),
+            Change::Modified { path, .. } => write!(f, "M {}", path),
+            Change::Renamed { from, to, .. } => write!(f, "R {} -> {}", from, to),
+        }
+    }
+}
+
+// Compares two versions by walking their sorted path lists side by side. A
+// removed path and an added path with the same blob id are reported as a
+// rename; when several share an id they are paired in path order. A change
+// of mode alone counts as a modification.
+pub fn diff(old: &Version, new: &Version) -> Vec<Change> {
+    let (lhs, rhs) = (old.paths(), new.paths());
+    let (mut i, mut j) = (0, 0);
+    let mut changes = Vec::new();
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+
+    while i < lhs.len() || j < rhs.len() {
+        let ordering = match (lhs.get(i), rhs.get(j)) {
+            (Some(l), Some(r)) => l.0.cmp(&r.0),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+
+        match ordering {
+            Ordering::Less => {
+                removed.push(&lhs[i]);
+                i += 1;
+            }
+            Ordering::Greater => {
+                added.push(&rhs[j]);
+                j += 1;
+            }
+            Ordering::Equal => {
+                let (path, from_mode, from_id) = &lhs[i];
+                let (_, to_mode, to_id) = &rhs[j];
+                if (from_mode, from_id) != (to_mode, to_id) {
+                    changes.push(Change::Modified {
+                        path: path.clone(),
+                        from: (*from_mode, *from_id),
+                        to: (*to_mode, *to_id),
+                    });
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    let mut removed_by_id: HashMap<(Mode, [u8; 32]), Vec<&str>> = HashMap::new();
+    for (path, mode, id) in removed.iter().rev() {
+        removed_by_id.entry((*mode, *id)).or_default().push(path);
+    }
+
+    for (path, mode, id) in added {
+        match removed_by_id.get_mut(&(*mode, *id)).and_then(|paths| paths.pop()) {
+            Some(from) => changes.push(Change::Renamed {
+                from: from.to_string(),
+                to: path.clone(),
+                mode: *mode,
+                id: *id,
+            }),
+            None => changes.push(Change::Added {
+                path: path.clone(),
+                mode: *mode,
+                id: *id,
+            }),
+        }
+    }
+
+    for (path, mode, id) in removed {
+        let remaining = removed_by_id.get(&(*mode, *id));
+        if remaining.is_some_and(|paths| paths.contains(&path.as_str())) {
+            changes.push(Change::Removed {
+                path: path.clone(),
+                mode: *mode,
+                id: *id,
+            });
+        }
+    }
+
+    changes.sort_by(|lhs, rhs| lhs.path().cmp(rhs.path()));
+    changes
+}
+
+async fn read_blob<S: ReadableStore>(store: &S, id: &[u8; 32]) -> anyhow::Result<Vec<u8>> {
+    match store.get(id).await? {
+        Some(Envelope::Blob(bytes)) => Ok(bytes),
+        Some(other) => bail!("expected a blob at {}, got a {}", hex::encode(id), other),
+        None => bail!("could not find blob {}", hex::encode(id)),
+    }
+}
+
+// Renders a unified diff for a modified path. Returns None for any other
+// kind of change, or when only the mode changed.
+pub async fn unified<S: ReadableStore>(store: &S, change: &Change) -> anyhow::Result<Option<String>> {
+    let (path, from, to) = match change {
+        Change::Modified {
+            path,
+            from: (_, from),
+            to: (_, to),
+        } => (path, from, to),
+        _ => return Ok(None),
+    };
+    if from == to {
+        return Ok(None);
+    }
+
+    let old = read_blob(store, from).await?;
+    let new = read_blob(store, to).await?;
+    let (old, new) = match (std::str::from_utf8(&old[..]), std::str::from_utf8(&new[..])) {
+        (Ok(old), Ok(new)) => (old, new),
+        _ => return Ok(Some(format!("Binary files a/{0} and b/{0} differ\n", path))),
+    };
+
+    Ok(Some(
+        TextDiff::from_lines(old, new)
+            .unified_diff()
+            .header(&format!("a/{}", path), &format!("b/{}", path))
+            .to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::version::VersionBuilder;
+    use crate::stores::WritableStore;
+    use crate::testing::scratch_store;
+    use sha2::Sha256;
+
+    #[test]
+    fn diff_detects_each_kind_of_change() {
+        let old = VersionBuilder::new("pkg", "1.0.0")
+            .path("README.md", &[1u8; 32])
+            .path("index.js", &[2u8; 32])
+            .path("bin/cli.js", &[7u8; 32])
+            .path("lib/a.js", &[3u8; 32])
+            .path("removed.js", &[4u8; 32])
+            .build();
+        let new = VersionBuilder::new("pkg", "1.1.0")
+            .path("README.md", &[1u8; 32])
+            .path("index.js", &[5u8; 32])
+            .entry("bin/cli.js", Mode::Executable, &[7u8; 32])
+            .path("src/a.js", &[3u8; 32])
+            .path("added.js", &[6u8; 32])
+            .build();
+
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                Change::Added {
+                    path: "added.js".to_string(),
+                    mode: Mode::Regular,
+                    id: [6u8; 32]
+                },
+                Change::Modified {
+                    path: "bin/cli.js".to_string(),
+                    from: (Mode::Regular, [7u8; 32]),
+                    to: (Mode::Executable, [7u8; 32])
+                },
+                Change::Modified {
+                    path: "index.js".to_string(),
+                    from: (Mode::Regular, [2u8; 32]),
+                    to: (Mode::Regular, [5u8; 32])
+                },
+                Change::Removed {
+                    path: "removed.js".to_string(),
+                    mode: Mode::Regular,
+                    id: [4u8; 32]
+                },
+                Change::Renamed {
+                    from: "lib/a.js".to_string(),
+                    to: "src/a.js".to_string(),
+                    mode: Mode::Regular,
+                    id: [3u8; 32]
+                },
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn unified_renders_modified_text() {
+        let store = scratch_store("diff-unified");
+        let mut ids = Vec::new();
+        for data in &["one\ntwo\n", "one\nthree\n"] {
+            let blob = Envelope::Blob(data.as_bytes().to_vec());
+            ids.push(blob.content_address::<Sha256>().0);
+            store.add(blob).await.unwrap();
+        }
+
+        let change = Change::Modified {
+            path: "a.txt".to_string(),
+            from: (Mode::Regular, ids[0]),
+            to: (Mode::Regular, ids[1]),
+        };
+        let text = unified(&store, &change).await.unwrap().unwrap();
+        assert!(text.starts_with("--- a/a.txt\n+++ b/a.txt\n"));
+        assert!(text.contains("-two\n+three\n"));
+    }
+}
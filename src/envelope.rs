@@ -1,6 +1,6 @@
 use sha2::Digest;
 use std::fmt::{Display, Formatter, Result as FmtResult};
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Envelope<T: AsRef<[u8]> + Send> {
     Blob(T),
     Version(T),
@@ -0,0 +1,238 @@
+use anyhow::{self, bail};
+use async_std::io::prelude::*;
+use async_std::io::{BufRead, BufReader, Write};
+use async_std::net::TcpStream;
+use thiserror::Error;
+
+pub mod server;
+
+// Just enough HTTP/1.1 to move objects around: one request per connection,
+// bodies delimited by Content-Length, no chunked encoding.
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+// Why a request was refused before it reached a route.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HttpError {
+    #[error("body of {0} bytes exceeds {1} bytes")]
+    BodyTooLarge(usize, usize),
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> Self {
+        self.headers
+            .push((key.as_ref().to_string(), value.as_ref().to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+pub(crate) fn header_value<'a>(headers: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.as_str())
+}
+
+// Reads one line of the head without letting the head as a whole grow past
+// MAX_HEAD_BYTES, given the `total` read so far.
+async fn read_head_line<R: BufRead + Unpin>(
+    input: &mut R,
+    line: &mut String,
+    total: usize,
+) -> anyhow::Result<usize> {
+    let limit = (MAX_HEAD_BYTES - total) as u64;
+    let read = (&mut *input).take(limit).read_line(line).await?;
+    if read as u64 == limit && !line.ends_with('\n') {
+        bail!("headers exceed {} bytes", MAX_HEAD_BYTES);
+    }
+    Ok(read)
+}
+
+// Reads the start line and headers, up to and including the blank line.
+async fn read_head<R: BufRead + Unpin>(
+    input: &mut R,
+) -> anyhow::Result<Option<(String, Vec<(String, String)>)>> {
+    let mut start = String::new();
+    if read_head_line(input, &mut start, 0).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut total = start.len();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = read_head_line(input, &mut line, total).await?;
+        if read == 0 {
+            bail!("unexpected eof reading headers");
+        }
+        total += read;
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+
+        match line.find(':') {
+            Some(idx) => headers.push((
+                line[..idx].trim().to_string(),
+                line[idx + 1..].trim().to_string(),
+            )),
+            None => bail!("malformed header line"),
+        }
+    }
+
+    Ok(Some((
+        start.trim_end_matches(['\r', '\n']).to_string(),
+        headers,
+    )))
+}
+
+async fn read_body<R: BufRead + Unpin>(
+    input: &mut R,
+    headers: &[(String, String)],
+) -> anyhow::Result<Vec<u8>> {
+    let len = match header_value(headers, "content-length") {
+        Some(len) => len.parse::<usize>()?,
+        None => return Ok(Vec::new()),
+    };
+    if len > MAX_BODY_BYTES {
+        return Err(HttpError::BodyTooLarge(len, MAX_BODY_BYTES).into());
+    }
+    let mut body = vec![0u8; len];
+    input.read_exact(&mut body[..]).await?;
+    Ok(body)
+}
+
+pub async fn read_request<R: BufRead + Unpin>(input: &mut R) -> anyhow::Result<Option<Request>> {
+    let (start, headers) = match read_head(input).await? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+
+    let mut parts = start.split(' ');
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), path.to_string())
+        }
+        _ => bail!("malformed request line: {:?}", start),
+    };
+
+    let body = read_body(input, &headers[..]).await?;
+    Ok(Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+// HEAD responses advertise the length of the body they would have sent, so
+// `include_body` is separate from the body itself.
+pub async fn write_response<W: Write + Unpin>(
+    output: &mut W,
+    response: &Response,
+    include_body: bool,
+) -> anyhow::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    for (key, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", key, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    head.push_str("Connection: close\r\n\r\n");
+
+    output.write_all(head.as_bytes()).await?;
+    if include_body {
+        output.write_all(&response.body[..]).await?;
+    }
+    output.flush().await?;
+    Ok(())
+}
+
+pub async fn write_request<W: Write + Unpin>(
+    output: &mut W,
+    method: &str,
+    host: &str,
+    path: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        host,
+        body.len()
+    );
+    output.write_all(head.as_bytes()).await?;
+    output.write_all(body).await?;
+    output.flush().await?;
+    Ok(())
+}
+
+pub async fn read_response<R: BufRead + Unpin>(input: &mut R) -> anyhow::Result<Response> {
+    let (start, headers) = match read_head(input).await? {
+        Some(head) => head,
+        None => bail!("connection closed before response"),
+    };
+
+    let mut parts = start.splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status.parse::<u16>()?,
+        _ => bail!("malformed status line: {:?}", start),
+    };
+
+    let body = read_body(input, &headers[..]).await?;
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+// Makes a single request against `host` (a "host:port" pair) and reads the
+// whole response.
+pub async fn fetch(host: &str, method: &str, path: &str, body: &[u8]) -> anyhow::Result<Response> {
+    let stream = TcpStream::connect(host).await?;
+    write_request(&mut &stream, method, host, path, body).await?;
+    read_response(&mut BufReader::new(&stream)).await
+}
@@ -0,0 +1,287 @@
+use crate::http::{read_request, write_response, HttpError, Request, Response};
+use crate::stores::ReadableStore;
+use crate::sync::write_pack_entry;
+use anyhow;
+use async_std::io::BufReader;
+use async_std::future;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How long a client has to send its whole request, and how long to wait
+// before accepting again when accepting fails, e.g. when out of descriptors.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// The most ids a single /pack request may ask for, so that the pack built in
+// memory for it stays bounded.
+pub const MAX_PACK_IDS: usize = 1024;
+
+// Routes:
+//
+// GET  /objects/<hex>  the object, as "<type> <len>\0<payload>"
+// HEAD /objects/<hex>  200 if present, 404 if not
+// POST /has            body: N * 32 byte ids; response: a bitmap with one bit
+//                      per id, set if present
+// POST /pack           body: N * 32 byte ids; response: a 4 byte big-endian
+//                      count followed by a pack stream of the objects that
+//                      were present; at most MAX_PACK_IDS ids, or 413
+//
+// Errors accepting or handling a connection do not stop the server; they are
+// passed to `report`.
+pub async fn serve<S, E>(listener: TcpListener, store: Arc<S>, report: E) -> anyhow::Result<()>
+where
+    S: 'static + ReadableStore + Send + Sync,
+    E: 'static + Fn(anyhow::Error) + Send + Sync,
+{
+    let report = Arc::new(report);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                report(anyhow::Error::new(e).context("error accepting connection"));
+                task::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let store = store.clone();
+        let report = report.clone();
+        task::spawn(async move {
+            if let Err(e) = handle(stream, &*store).await {
+                report(e.context("error handling request"));
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle<S: ReadableStore + Sync>(stream: TcpStream, store: &S) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let request = match future::timeout(REQUEST_TIMEOUT, read_request(&mut reader)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) => return Ok(()),
+        Ok(Err(e)) => {
+            let status = match e.downcast_ref::<HttpError>() {
+                Some(HttpError::BodyTooLarge(..)) => 413,
+                None => 400,
+            };
+            return write_response(&mut &stream, &Response::new(status), true).await;
+        }
+        Err(_) => {
+            return write_response(&mut &stream, &Response::new(408), true).await;
+        }
+    };
+
+    let response = match route(&request, store).await {
+        Ok(response) => response,
+        Err(e) => Response::new(500).body(format!("{}\n", e).into_bytes()),
+    };
+    write_response(&mut &stream, &response, request.method != "HEAD").await
+}
+
+fn parse_id(hash: &str) -> Option<[u8; 32]> {
+    let decoded = hex::decode(hash).ok()?;
+    if decoded.len() != 32 {
+        return None;
+    }
+    let mut id = [0u8; 32];
+    id.copy_from_slice(&decoded[..]);
+    Some(id)
+}
+
+fn parse_ids(body: &[u8]) -> Option<Vec<[u8; 32]>> {
+    if !body.len().is_multiple_of(32) {
+        return None;
+    }
+    Some(
+        body.chunks(32)
+            .map(|chunk| {
+                let mut id = [0u8; 32];
+                id.copy_from_slice(chunk);
+                id
+            })
+            .collect(),
+    )
+}
+
+pub async fn route<S: ReadableStore + Sync>(
+    request: &Request,
+    store: &S,
+) -> anyhow::Result<Response> {
+    let method = request.method.as_str();
+    let path = request.path.as_str();
+
+    if let Some(hash) = path.strip_prefix("/objects/") {
+        if method != "GET" && method != "HEAD" {
+            return Ok(Response::new(405));
+        }
+        let id = match parse_id(hash) {
+            Some(id) => id,
+            None => return Ok(Response::new(400)),
+        };
+        return Ok(match store.get(id).await? {
+            Some(object) => {
+                let payload = object.payload_bytes();
+                let mut body = format!("{} {}\0", object, payload.len()).into_bytes();
+                body.extend_from_slice(&payload[..]);
+                Response::new(200)
+                    .header("Content-Type", "application/octet-stream")
+                    .body(body)
+            }
+            None => Response::new(404),
+        });
+    }
+
+    match path {
+        "/has" | "/pack" if method != "POST" => Ok(Response::new(405)),
+        "/has" => {
+            let ids = match parse_ids(&request.body[..]) {
+                Some(ids) => ids,
+                None => return Ok(Response::new(400)),
+            };
+            let mut bitmap = vec![0u8; ids.len().div_ceil(8)];
+            for (idx, id) in ids.iter().enumerate() {
+                if store.get(id).await?.is_some() {
+                    bitmap[idx >> 3] |= 1 << (idx & 7);
+                }
+            }
+            Ok(Response::new(200)
+                .header("Content-Type", "application/octet-stream")
+                .body(bitmap))
+        }
+        "/pack" => {
+            let ids = match parse_ids(&request.body[..]) {
+                Some(ids) => ids,
+                None => return Ok(Response::new(400)),
+            };
+            if ids.len() > MAX_PACK_IDS {
+                return Ok(Response::new(413));
+            }
+            let mut objects = Vec::new();
+            for id in ids {
+                if let Some(object) = store.get(id).await? {
+                    objects.push(object);
+                }
+            }
+            let mut body = (objects.len() as u32).to_be_bytes().to_vec();
+            for object in &objects {
+                write_pack_entry(&mut body, object).await?;
+            }
+            Ok(Response::new(200)
+                .header("Content-Type", "application/x-eos-pack")
+                .body(body))
+        }
+        _ => Ok(Response::new(404)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::stores::WritableStore;
+    use crate::sync::read_pack_entry;
+    use crate::testing::scratch_store;
+    use sha2::Sha256;
+    use std::net::SocketAddr;
+
+    async fn start(name: &str) -> (SocketAddr, [u8; 32]) {
+        let store = scratch_store(name);
+        let blob = Envelope::Blob(b"hello world".to_vec());
+        let (id, _) = blob.content_address::<Sha256>();
+        store.add(blob).await.expect("failed to add blob");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(serve(listener, Arc::new(store), |_| {}));
+        (addr, id)
+    }
+
+    async fn request(addr: SocketAddr, head: &str, body: &[u8]) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let head = format!("{}\r\nContent-Length: {}\r\n\r\n", head, body.len());
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("no end of headers");
+        let status = String::from_utf8_lossy(&response[..split])
+            .lines()
+            .next()
+            .unwrap()
+            .to_string();
+        (status, response[split + 4..].to_vec())
+    }
+
+    #[async_std::test]
+    async fn serves_objects() {
+        let (addr, id) = start("serve-objects").await;
+        let path = format!("/objects/{}", hex::encode(id));
+
+        let (status, body) = request(addr, &format!("GET {} HTTP/1.1", path), b"").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(&body[..], &b"blob 11\0hello world"[..]);
+
+        let (status, body) = request(addr, &format!("HEAD {} HTTP/1.1", path), b"").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.is_empty());
+
+        let missing = format!("GET /objects/{} HTTP/1.1", hex::encode([0u8; 32]));
+        let (status, _) = request(addr, &missing, b"").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[async_std::test]
+    async fn serves_batches() {
+        let (addr, id) = start("serve-batches").await;
+        let mut ids = id.to_vec();
+        ids.extend_from_slice(&[0u8; 32]);
+
+        let (status, body) = request(addr, "POST /has HTTP/1.1", &ids[..]).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, vec![0x01]);
+
+        let (status, body) = request(addr, "POST /pack HTTP/1.1", &ids[..]).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(&body[0..4], &1u32.to_be_bytes()[..]);
+        let object = read_pack_entry(&mut &body[4..]).await.unwrap();
+        assert_eq!(object.content_address::<Sha256>().0, id);
+    }
+
+    #[async_std::test]
+    async fn refuses_oversized_requests() {
+        let (addr, _) = start("serve-oversized").await;
+        let ids = vec![0u8; 32 * (MAX_PACK_IDS + 1)];
+        let (status, _) = request(addr, "POST /pack HTTP/1.1", &ids[..]).await;
+        assert_eq!(status, "HTTP/1.1 413 Payload Too Large");
+
+        // A body is refused by its length, before any of it is sent; a length
+        // that is not a number is merely malformed.
+        for (length, expected) in &[("1000000000000", "413"), ("many", "400")] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let head = format!("POST /has HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length);
+            stream.write_all(head.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {} ", expected)));
+        }
+    }
+
+    #[async_std::test]
+    async fn heads_are_bounded() {
+        // Neither an endless start line nor an endless header is read whole.
+        let mut input = BufReader::new(async_std::io::repeat(b'a'));
+        assert!(read_request(&mut input).await.is_err());
+
+        let head = async_std::io::Cursor::new(b"GET / HTTP/1.1\r\nX-Long: ".to_vec());
+        let mut input = BufReader::new(head.chain(async_std::io::repeat(b'a')));
+        assert!(read_request(&mut input).await.is_err());
+    }
+}
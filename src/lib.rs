@@ -1,17 +1,23 @@
+pub mod dag;
+pub mod diff;
 pub mod envelope;
 pub mod errors;
 pub mod objects;
+pub mod package;
 pub mod stores;
 pub mod keys;
+pub mod resolve;
+pub mod http;
+pub mod snapshot;
+pub mod spec;
+pub mod sync;
+pub mod tarball;
+pub mod verify;
 
-pub trait PackageArg {}
+#[cfg(test)]
+pub(crate) mod testing;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct AliasArg<T: PackageArg + Clone + std::fmt::Debug + Eq + PartialEq> {
-    name: String,
-    package: T,
-}
-impl<T: PackageArg + Clone + std::fmt::Debug + Eq + PartialEq> PackageArg for AliasArg<T> {}
+pub use spec::{AliasArg, PackageArg};
 
 #[cfg(test)]
 mod tests {
@@ -3,56 +3,21 @@ use anyhow::bail;
 use sodiumoxide::crypto::sign::ed25519::{
     sign_detached, verify_detached, PublicKey, SecretKey, Signature,
 };
-use std::collections::{ BinaryHeap, HashSet };
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use std::cmp::Reverse;
+use std::collections::{ BTreeSet, BinaryHeap, HashMap, HashSet };
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::io::{Cursor, Read, Write};
 use std::ops::{BitAnd, BitOrAssign};
 use thiserror::Error;
 use crate::envelope::Envelope;
+use crate::package::{self, Conflict, PackageState};
 use chrono::prelude::*;
-
-// The varint crate let me down. This could be better/faster.
-fn read_varint<R: Read>(r: &mut R) -> anyhow::Result<u64> {
-    let mut byt = [0u8; 1];
-    let mut shift = 0;
-    let mut accum = 0u64;
-    let mut mask = 0x7fu64;
-    while {
-        r.read_exact(&mut byt)?;
-
-        let item = byt[0] as u64;
-        accum |= (item & mask) << (shift * 7);
-        shift += 1;
-        item & 0x80 != 0
-    } {}
-    Ok(accum)
-}
-
-fn read_varint_string<R: Read>(r: &mut R) -> anyhow::Result<String> {
-    let len: u64 = read_varint(r)?;
-    let mut str_vec = vec![0; len as usize];
-    r.read_exact(&mut str_vec);
-    Ok(String::from_utf8(str_vec)?)
-}
-
-fn write_varint<W: Write, I: Into<u64>>(w: &mut W, input: I) -> anyhow::Result<usize> {
-    const MSB_ALL: u64 = !0x7fu64;
-    let mut input_u64: u64 = input.into();
-    let mut bytes: Vec<u8> = Vec::with_capacity(8);
-    while input_u64 & MSB_ALL > 0 {
-        bytes.push(((input_u64 & 0xFF) as u8) | 0x80u8);
-        input_u64 >>= 7;
-    }
-    bytes.push((input_u64 & 0x7F) as u8);
-    w.write_all(&bytes[..])?;
-    Ok(bytes.len())
-}
-
-fn write_varint_str<W: Write>(w: &mut W, s: &str) -> anyhow::Result<usize> {
-    let bytes = s.as_bytes();
-    let mut written = write_varint(w, bytes.len() as u64)?;
-    w.write_all(bytes)?;
-    Ok((written + bytes.len()) as usize)
-}
+use crate::objects::varint::{
+    read_varint, read_varint_bytes, read_varint_string, write_varint, write_varint_str,
+};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Claim {
@@ -65,6 +30,9 @@ pub enum Claim {
     Other { typeno: u64, data: Vec<u8> },
 }
 
+// The type numbers of every claim but `Other`, which may not reuse them.
+const KNOWN_CLAIM_TYPES: [u64; 6] = [0x01, 0x02, 0x08, 0x10, 0x20, 0x40];
+
 impl Claim {
     pub fn bitmask(&self) -> u8 {
         match self {
@@ -137,9 +105,12 @@ impl Claim {
             }
 
             Claim::Other { typeno, data } => {
+                if KNOWN_CLAIM_TYPES.contains(typeno) {
+                    bail!("claim type {} is reserved", typeno);
+                }
                 written += write_varint(destination, *typeno)?;
                 written += data.len();
-                destination.write_all(&data[..]);
+                destination.write_all(&data[..])?;
             }
         }
 
@@ -153,15 +124,15 @@ impl Claim {
         // date := varint date
         // yank := varint version varint reason
         // unyank := varint version
-        // tag := varint tag varint version
-        // publish := varint version 32bytes id
+        // tag := varint version varint tag
+        // publish := varint version varint id
         // other := read the rest of the bytes
+        //
+        // Everything but `other` must account for every byte of the input.
         let bytes = input.as_ref();
-        let mut capacity = bytes.len();
         let mut cursor = Cursor::new(bytes);
         let claim_type = read_varint(&mut cursor)?;
-        capacity -= cursor.position() as usize;
-        Ok(match claim_type {
+        let claim = match claim_type {
             0x01 => Claim::AuthorityAdd {
                 public_key: read_varint_string(&mut cursor)?,
                 name: read_varint_string(&mut cursor)?,
@@ -177,29 +148,60 @@ impl Claim {
                 version: read_varint_string(&mut cursor)?,
             },
             0x20 => Claim::Tag {
+                version: read_varint_string(&mut cursor)?,
                 tag: read_varint_string(&mut cursor)?,
+            },
+            0x40 => Claim::Publication {
                 version: read_varint_string(&mut cursor)?,
+                id: read_varint_bytes(&mut cursor)?,
             },
-            0x40 => {
-                let version = read_varint_string(&mut cursor)?;
-                let mut id = [0u8; 32];
-                cursor.read_exact(&mut id)?;
-                Claim::Publication {
-                    version,
-                    id: id.to_vec(),
-                }
-            }
             typeno => {
-                let mut rest = Vec::with_capacity(capacity);
+                let mut rest = Vec::new();
                 cursor.read_to_end(&mut rest)?;
                 Claim::Other { typeno, data: rest }
             }
-        })
+        };
+
+        if cursor.position() as usize != bytes.len() {
+            bail!("trailing bytes after claim of type {}", claim_type);
+        }
+        Ok(claim)
+    }
+}
+
+// No claim uses the 0x04 bit of the claimset, so events written before the
+// encoding was versioned never start with it.
+const VERSIONED_EVENT: u8 = 0x04;
+const LEGACY_FORMAT: u8 = 0;
+// Like the legacy format, records `at` in whole seconds.
+const SECONDS_FORMAT: u8 = 1;
+// Follows the seconds of `at` with its nanoseconds.
+const FORMAT_VERSION: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    Ed25519,
+}
+
+impl SignatureAlgorithm {
+    fn from_id(id: u64) -> anyhow::Result<Self> {
+        match id {
+            1 => Ok(SignatureAlgorithm::Ed25519),
+            _ => bail!("unknown signature algorithm {}", id),
+        }
+    }
+
+    fn to_id(self) -> u64 {
+        match self {
+            SignatureAlgorithm::Ed25519 => 1,
+        }
     }
 }
 
 #[derive(PartialEq, Debug)]
 pub struct Event {
+    format: u8,
+    algorithm: SignatureAlgorithm,
     claimset: u8,
     at: DateTime<Utc>,
     claims: Vec<Claim>,
@@ -210,8 +212,11 @@ pub struct Event {
 
 impl Event {
     pub fn from_bytes<T: AsRef<[u8]> + Send>(input: T) -> anyhow::Result<Self> {
+        // VERSIONED_EVENT(u8)
+        // format version(u8)
         // CLAIM_BITMASK(u8)
         // at(i64)
+        // at nanoseconds(u32, from format 2)
         // parent hashes(varint u32)
         // parent hashes * 32 * N
         // claims(varint u32)
@@ -221,14 +226,31 @@ impl Event {
         //      claim data
         // signatory length(varint u32)
         // signatory
+        // signature algorithm(varint u32)
+        // signature length(varint u32)
         // signature
-        // TODO(chrisdickinson): note the lack of a signature algo string. We
-        // should add that.
-
-        // signature type, null, payload type + len
-        let bytes = input.as_ref();
+        //
+        // Legacy events start at the claimset, have no algorithm (ed25519 is
+        // implied), and take the rest of the input as their signature.
+        //
+        // Decoding is strict so that every event has exactly one encoding:
+        // the claimset must be the union of the claims' bitmasks, versioned
+        // events list their parents in ascending order, and nothing may
+        // follow the signature.
+        let mut bytes = input.as_ref();
+
+        let mut format = LEGACY_FORMAT;
+        if bytes.first() == Some(&VERSIONED_EVENT) {
+            format = match bytes.get(1) {
+                Some(&SECONDS_FORMAT) => SECONDS_FORMAT,
+                Some(&FORMAT_VERSION) => FORMAT_VERSION,
+                Some(version) => bail!("unsupported event format version {}", version),
+                None => bail!("EOF while reading event format version"),
+            };
+            bytes = &bytes[2..];
+        }
 
-        if bytes.len() < 1 {
+        if bytes.is_empty() {
             bail!("EOF while reading claimset mask");
         }
 
@@ -237,35 +259,69 @@ impl Event {
 
         let mut at_bytes = [0u8; 8];
         cursor.read_exact(&mut at_bytes)?;
-        let mut at = Utc.timestamp(i64::from_be_bytes(at_bytes), 0);
+        let mut nanos = 0;
+        if format == FORMAT_VERSION {
+            let mut nanos_bytes = [0u8; 4];
+            cursor.read_exact(&mut nanos_bytes)?;
+            nanos = u32::from_be_bytes(nanos_bytes);
+            if nanos >= 1_000_000_000 {
+                bail!("event timestamp has {} nanoseconds", nanos);
+            }
+        }
+        let at = match Utc.timestamp_opt(i64::from_be_bytes(at_bytes), nanos).single() {
+            Some(at) => at,
+            None => bail!("event timestamp is out of range"),
+        };
 
-        let parent_count = read_varint(&mut cursor)? as usize;
-        let mut parents = Vec::with_capacity(parent_count);
-        while parents.len() < parent_count {
+        let parent_count = read_varint(&mut cursor)?;
+        let mut parents: Vec<[u8; 32]> = Vec::new();
+        while (parents.len() as u64) < parent_count {
             let mut parent_oid = [0; 32];
             cursor.read_exact(&mut parent_oid)?;
+            let ascending = match parents.last() {
+                Some(last) => *last < parent_oid,
+                None => true,
+            };
+            if format != LEGACY_FORMAT && !ascending {
+                bail!("event parents are not in ascending order");
+            }
             parents.push(parent_oid);
         }
 
-        let claim_count = read_varint(&mut cursor)? as usize;
+        let claim_count = read_varint(&mut cursor)?;
         let mut claims = Vec::new();
-        while claims.len() < claim_count {
-            let claim_length = read_varint(&mut cursor)? as usize;
-            let mut claim_vec = vec![0; claim_length];
-            cursor.read_exact(&mut claim_vec)?;
-            let claim = Claim::from_bytes(&claim_vec[..])?;
+        let mut expected_claimset = 0;
+        while (claims.len() as u64) < claim_count {
+            let claim = Claim::from_bytes(read_varint_bytes(&mut cursor)?)?;
+            expected_claimset |= claim.bitmask();
             claims.push(claim);
         }
+        if claimset != expected_claimset {
+            bail!(
+                "claimset {:#04x} does not match the claims ({:#04x})",
+                claimset,
+                expected_claimset
+            );
+        }
 
-        let signatory_len = read_varint(&mut cursor)? as usize;
-        let mut signatory_vec = vec![0; signatory_len];
-        cursor.read_exact(&mut signatory_vec)?;
-        let signatory = String::from_utf8(signatory_vec)?;
-
-        let mut signature = Vec::new();
-        cursor.read_to_end(&mut signature)?;
+        let signatory = read_varint_string(&mut cursor)?;
+
+        let (algorithm, signature) = if format == LEGACY_FORMAT {
+            let mut signature = Vec::new();
+            cursor.read_to_end(&mut signature)?;
+            (SignatureAlgorithm::Ed25519, signature)
+        } else {
+            let algorithm = SignatureAlgorithm::from_id(read_varint(&mut cursor)?)?;
+            let signature = read_varint_bytes(&mut cursor)?;
+            if cursor.position() as usize != bytes.len() - 1 {
+                bail!("trailing bytes after event signature");
+            }
+            (algorithm, signature)
+        };
 
         return Ok(Event {
+            format,
+            algorithm,
             claims,
             at,
             signature,
@@ -276,12 +332,27 @@ impl Event {
     }
 
     pub fn to_bytes_unsigned<W: Write>(&self, destination: &mut W) -> anyhow::Result<usize> {
+        let mut written = 0;
+        if self.format != LEGACY_FORMAT {
+            written += 2;
+            destination.write_all(&[VERSIONED_EVENT, self.format])?;
+        }
+
         let claimset_buf = [self.claimset; 1];
-        let mut written = 1 as usize;
+        written += 1;
         destination.write_all(&claimset_buf)?;
 
         let at_bytes = self.at.timestamp().to_be_bytes();
+        written += at_bytes.len();
         destination.write_all(&at_bytes)?;
+        if self.format == FORMAT_VERSION {
+            let nanos = self.at.timestamp_subsec_nanos();
+            if nanos >= 1_000_000_000 {
+                bail!("leap seconds cannot be encoded");
+            }
+            written += 4;
+            destination.write_all(&nanos.to_be_bytes())?;
+        }
 
         written += write_varint(destination, self.parents.len() as u64)?;
         for parent in self.parents.iter() {
@@ -294,7 +365,7 @@ impl Event {
             let mut buf = Vec::new();
             claim.to_bytes(&mut buf)?;
             written += write_varint(destination, buf.len() as u64)?;
-            destination.write_all(&buf[..]);
+            destination.write_all(&buf[..])?;
             written += buf.len();
         }
 
@@ -303,29 +374,61 @@ impl Event {
         written += signatory_slice.len();
         destination.write_all(signatory_slice)?;
 
+        if self.format != LEGACY_FORMAT {
+            written += write_varint(destination, self.algorithm.to_id())?;
+        }
+
         Ok(written)
     }
 
     pub fn to_bytes<W: Write>(&self, destination: &mut W) -> anyhow::Result<usize> {
         let mut written = self.to_bytes_unsigned(destination)?;
+        if self.format != LEGACY_FORMAT {
+            written += write_varint(destination, self.signature.len() as u64)?;
+        }
         written += self.signature.len();
         destination.write_all(&self.signature[..])?;
         Ok(written)
     }
 
+    pub fn parents(&self) -> &[[u8; 32]] {
+        &self.parents[..]
+    }
+
+    pub fn claims(&self) -> &[Claim] {
+        &self.claims[..]
+    }
+
+    pub fn signatory(&self) -> &str {
+        &self.signatory
+    }
+
+    pub fn at(&self) -> &DateTime<Utc> {
+        &self.at
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+
+    pub fn signature(&self) -> &[u8] {
+        &self.signature[..]
+    }
+
     pub fn verify(&self, pk: &PublicKey) -> anyhow::Result<bool> {
         let mut buf = Vec::new();
         self.to_bytes_unsigned(&mut buf)?;
 
-        let mut signature_bytes = [0; 64];
-        signature_bytes.copy_from_slice(&self.signature[0..64]);
-        let sig = Signature(signature_bytes);
+        let sig = match Signature::from_bytes(&self.signature[..]) {
+            Ok(sig) => sig,
+            Err(_) => bail!("malformed signature"),
+        };
         Ok(verify_detached(&sig, &buf[..], pk))
     }
 }
 
 #[derive(Error, Debug)]
-enum EventBuilderError {
+pub enum EventBuilderError {
     #[error("Attempt to remove authority \"{0}\" which is not a currrent authority")]
     RemovedAuthorityDoesNotExist(String),
     #[error("Yanked version \"{0}\" does not exist")]
@@ -338,11 +441,13 @@ enum EventBuilderError {
     AuthorityNameConflict(String),
     #[error("Provided secret key is not an authority")]
     NotAuthoritative,
+    #[error("Genesis event does not make its signatory \"{0}\" an authority")]
+    NotSelfAuthorising(String),
 }
 
 pub struct EventBuilder {
     claims: Vec<Claim>,
-    parents: HashSet<[u8; 32]>,
+    parents: BTreeSet<[u8; 32]>,
     claimset: u8,
     at: Option<DateTime<Utc>>,
     error: Option<EventBuilderError>,
@@ -353,7 +458,7 @@ impl EventBuilder {
         EventBuilder {
             claims: Vec::new(),
             at: None,
-            parents: HashSet::new(),
+            parents: BTreeSet::new(),
             claimset: 0,
             error: None,
         }
@@ -379,6 +484,54 @@ impl EventBuilder {
         self
     }
 
+    // Starts an event that joins `heads`, returning the conflicts between
+    // them that the merge settles: writes to one subject from events that no
+    // single head already had both of. Conflicts that an earlier merge saw
+    // are not repeated.
+    pub fn merge<R: ReadableStore>(
+        store: &R,
+        heads: &[[u8; 32]],
+    ) -> anyhow::Result<(Self, Vec<Conflict>)> {
+        let events = package::history_sync(store, heads)?;
+        let (_, conflicts) = package::replay(&events)?;
+
+        let parents: HashMap<_, _> = events
+            .iter()
+            .map(|(id, event)| (*id, event.parents()))
+            .collect();
+        let mut histories = Vec::new();
+        for head in heads {
+            let mut history = HashSet::new();
+            let mut pending = vec![*head];
+            while let Some(id) = pending.pop() {
+                if history.insert(id) {
+                    pending.extend(parents[&id].iter().cloned());
+                }
+            }
+            histories.push(history);
+        }
+        let conflicts = conflicts
+            .into_iter()
+            .filter(|conflict| {
+                !histories.iter().any(|history| {
+                    history.contains(&conflict.kept) && history.contains(&conflict.dropped)
+                })
+            })
+            .collect();
+
+        let mut builder = EventBuilder::new();
+        for head in heads {
+            builder = builder.parent(head);
+        }
+        Ok((builder, conflicts))
+    }
+
+    // Signs the event after checking it against the state its parents
+    // describe, loaded from `store`. The signatory must name a current
+    // authority whose key is `sk` unless the event has no parents, in which
+    // case it founds the package and must add that authority itself. Claims
+    // are checked in order, so an event may publish a version and tag it in
+    // one go.
     pub fn sign<T, R>(self, signatory: T, sk: &SecretKey, store: &R) -> anyhow::Result<Event>
     where
         T: AsRef<str>,
@@ -388,31 +541,91 @@ impl EventBuilder {
             return Err(err.into());
         }
 
+        let parents: Vec<_> = self.parents.into_iter().collect();
+        let mut state = PackageState::load_sync(store, &parents[..])?;
+        let signatory = signatory.as_ref();
+        if !parents.is_empty() && !state.is_authority(signatory, &sk.public_key()) {
+            return Err(EventBuilderError::NotAuthoritative.into());
+        }
+        for claim in self.claims.iter() {
+            check_claim(&state, claim)?;
+            state.apply_claim(claim)?;
+        }
+        if parents.is_empty() && !state.is_authority(signatory, &sk.public_key()) {
+            return Err(EventBuilderError::NotSelfAuthorising(signatory.to_string()).into());
+        }
+
+        Self::seal(self.claims, self.claimset, self.at, parents, signatory, sk)
+    }
+
+    // Signs the event without checking it, to build the kind of history
+    // `sign` refuses to write but peers may still send.
+    #[cfg(test)]
+    pub(crate) fn sign_unchecked(self, signatory: &str, sk: &SecretKey) -> Event {
+        let parents = self.parents.into_iter().collect();
+        Self::seal(self.claims, self.claimset, self.at, parents, signatory, sk)
+            .expect("failed to sign")
+    }
+
+    fn seal(
+        claims: Vec<Claim>,
+        claimset: u8,
+        at: Option<DateTime<Utc>>,
+        parents: Vec<[u8; 32]>,
+        signatory: &str,
+        sk: &SecretKey,
+    ) -> anyhow::Result<Event> {
         let mut event = Event {
-            at: self.at.unwrap_or_else(|| Utc::now()),
-            claimset: self.claimset,
-            claims: self.claims,
-            parents: self.parents.into_iter().collect(),
-            signatory: String::from(signatory.as_ref()),
+            format: FORMAT_VERSION,
+            algorithm: SignatureAlgorithm::Ed25519,
+            at: at.unwrap_or_else(Utc::now),
+            claimset,
+            claims,
+            parents,
+            signatory: String::from(signatory),
             signature: Vec::new(),
         };
 
         let mut unsigned_event_bytes = Vec::new();
         let written = event.to_bytes_unsigned(&mut unsigned_event_bytes)?;
         let sig = sign_detached(&unsigned_event_bytes[..], sk);
-        event.signature = sig.0.to_vec();
+        event.signature = sig.to_bytes().to_vec();
 
-        // TODO: validation of the new signed event: are we an authority?
-        // are our claims valid? etc.
         Ok(event)
     }
 }
 
+fn check_claim(state: &PackageState, claim: &Claim) -> Result<(), EventBuilderError> {
+    match claim {
+        Claim::AuthorityAdd { public_key, name } => match state.authority(name) {
+            Some(existing) if existing != public_key => {
+                Err(EventBuilderError::AuthorityNameConflict(name.clone()))
+            }
+            _ => Ok(()),
+        },
+        Claim::AuthorityRemove { name } if state.authority(name).is_none() => {
+            Err(EventBuilderError::RemovedAuthorityDoesNotExist(name.clone()))
+        }
+        Claim::Yank { version, .. } if state.version(version).is_none() => {
+            Err(EventBuilderError::YankedVersionDoesNotExist(version.clone()))
+        }
+        Claim::Unyank { version } if !state.is_yanked(version) => {
+            Err(EventBuilderError::UnyankedVersionNotYanked(version.clone()))
+        }
+        Claim::Tag { version, .. } if state.version(version).is_none() => {
+            Err(EventBuilderError::TaggedVersionDoesNotExist(version.clone()))
+        }
+        _ => Ok(()),
+    }
+}
+
 pub struct IdEvent([u8; 32], Event);
 
+// Orders by timestamp, then by id, so events from the same instant still have
+// a fixed order.
 impl std::cmp::Ord for IdEvent {
     fn cmp(&self, other: &IdEvent) -> std::cmp::Ordering {
-        self.1.at.cmp(&other.1.at)
+        (self.1.at, self.0).cmp(&(other.1.at, other.0))
     }
 }
 
@@ -430,55 +643,711 @@ impl std::cmp::PartialEq for IdEvent {
 
 impl std::cmp::Eq for IdEvent { }
 
+// How an event walk orders the events it finds. Parents are always found by
+// id, never by clock, but a walk without limits loads events only as it needs
+// them and yields what it can along the way; see `Walker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Traversal {
+    // Children before parents, newest first among the events whose loaded
+    // children have all been yielded. An event is only loaded once every
+    // loaded child of it is out, so one dated after a descendant it has not
+    // met yet can come out before that descendant.
+    Newest,
+    // Parents before children, oldest first among the loaded events whose
+    // parents have all been yielded. Timestamps only break ties here, so a
+    // forged `at` cannot put an event before its parents.
+    Topological,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Walk {
+    traversal: Traversal,
+    max_depth: Option<usize>,
+    max_events: Option<usize>,
+}
+
+impl Walk {
+    fn new() -> Self {
+        Walk {
+            traversal: Traversal::Newest,
+            max_depth: None,
+            max_events: None,
+        }
+    }
+
+    fn is_limited(&self) -> bool {
+        self.max_depth.is_some() || self.max_events.is_some()
+    }
+
+    // The heads the walk starts from, after dropping repeats and any beyond
+    // the event limit.
+    fn heads(&self, heads: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        let mut seen = HashSet::new();
+        heads
+            .iter()
+            .filter(|head| seen.insert(**head))
+            .take(self.max_events.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    // The parents of `level`, which sits `depth` hops from the heads, that
+    // the walk should load next. Parents are taken in id order, so when the
+    // event limit cuts a level short the same ones are kept every time.
+    fn next_level(
+        &self,
+        depth: usize,
+        loaded: &HashMap<[u8; 32], Event>,
+        level: &[[u8; 32]],
+    ) -> Vec<[u8; 32]> {
+        if matches!(self.max_depth, Some(max) if depth >= max) {
+            return Vec::new();
+        }
+        let mut next = BTreeSet::new();
+        for id in level {
+            for parent in loaded[id].parents() {
+                if !loaded.contains_key(parent) {
+                    next.insert(*parent);
+                }
+            }
+        }
+        let room = match self.max_events {
+            Some(max) => max.saturating_sub(loaded.len()),
+            None => usize::MAX,
+        };
+        next.into_iter().take(room).collect()
+    }
+
+    fn order(&self, loaded: HashMap<[u8; 32], Event>) -> Vec<([u8; 32], Event)> {
+        // Each event waits for the events that must come out before it: its
+        // children when walking newest first, its parents otherwise.
+        let mut waiting: HashMap<[u8; 32], usize> = HashMap::new();
+        let mut unblocks: HashMap<[u8; 32], Vec<[u8; 32]>> = HashMap::new();
+        for (id, event) in loaded.iter() {
+            for parent in event.parents() {
+                if !loaded.contains_key(parent) {
+                    continue;
+                }
+                let (first, then) = match self.traversal {
+                    Traversal::Newest => (*id, *parent),
+                    Traversal::Topological => (*parent, *id),
+                };
+                *waiting.entry(then).or_default() += 1;
+                unblocks.entry(first).or_default().push(then);
+            }
+        }
+
+        match self.traversal {
+            Traversal::Newest => drain(loaded, waiting, unblocks, |event| event, |event| event),
+            Traversal::Topological => {
+                drain(loaded, waiting, unblocks, Reverse, |Reverse(event)| event)
+            }
+        }
+    }
+}
+
+// Yields events once nothing they wait on is left, taking the greatest `K`
+// among those that are ready.
+fn drain<K: Ord>(
+    mut loaded: HashMap<[u8; 32], Event>,
+    mut waiting: HashMap<[u8; 32], usize>,
+    mut unblocks: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    wrap: fn(IdEvent) -> K,
+    unwrap: fn(K) -> IdEvent,
+) -> Vec<([u8; 32], Event)> {
+    let mut ready = BinaryHeap::new();
+    let ids: Vec<_> = loaded.keys().cloned().collect();
+    for id in ids {
+        if !waiting.contains_key(&id) {
+            let event = loaded.remove(&id).unwrap();
+            ready.push(wrap(IdEvent(id, event)));
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(loaded.len() + ready.len());
+    while let Some(next) = ready.pop() {
+        let IdEvent(id, event) = unwrap(next);
+        for then in unblocks.remove(&id).unwrap_or_default() {
+            let count = waiting.get_mut(&then).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                let event = loaded.remove(&then).unwrap();
+                ready.push(wrap(IdEvent(then, event)));
+            }
+        }
+        ordered.push((id, event));
+    }
+    ordered
+}
+
+// What a walk wants next from whoever drives it.
+enum Step {
+    Yield([u8; 32], Event),
+    // The walk cannot go on until this event is passed to `Walker::loaded`,
+    // or `None` if the store does not have it.
+    Load([u8; 32]),
+    // An event could not be read; the walk ends here.
+    Fail(anyhow::Error),
+    Done,
+}
+
+// An event walk, apart from the store it reads, so that `EventIterator` and
+// `EventStream` can share it. With limits, everything within them is loaded
+// before anything is yielded, since the walk must know which events are
+// closest to the heads. Without limits, events are loaded only as the walk
+// needs them, so taking the first few of a long history reads little of it:
+// newest first, an event is loaded once its loaded children are out; parents
+// first, the walk loads depth first until some event is ready.
+enum Walker {
+    Collecting(Collector),
+    Ordered(std::vec::IntoIter<([u8; 32], Event)>),
+    Lazy(Frontier),
+    Failed(Option<anyhow::Error>),
+}
+
+impl Walker {
+    fn new(walk: Walk, heads: Vec<([u8; 32], Event)>) -> Self {
+        if !walk.is_limited() {
+            return Walker::Lazy(Frontier::new(walk.traversal, heads));
+        }
+        Walker::Collecting(Collector {
+            walk,
+            level: heads.iter().map(|(id, _)| *id).collect(),
+            loaded: heads.into_iter().collect(),
+            next: Vec::new(),
+            queue: Vec::new(),
+            depth: 0,
+        })
+    }
+
+    fn step(&mut self) -> Step {
+        loop {
+            let ordered = match self {
+                Walker::Collecting(collector) => match collector.step() {
+                    Some(id) => return Step::Load(id),
+                    None => {
+                        let loaded = std::mem::take(&mut collector.loaded);
+                        collector.walk.order(loaded).into_iter()
+                    }
+                },
+                Walker::Ordered(ordered) => {
+                    return match ordered.next() {
+                        Some((id, event)) => Step::Yield(id, event),
+                        None => Step::Done,
+                    }
+                }
+                Walker::Lazy(frontier) => return frontier.step(),
+                Walker::Failed(err) => {
+                    return match err.take() {
+                        Some(err) => Step::Fail(err),
+                        None => Step::Done,
+                    }
+                }
+            };
+            *self = Walker::Ordered(ordered);
+        }
+    }
+
+    fn loaded(&mut self, id: [u8; 32], event: anyhow::Result<Option<Event>>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                *self = Walker::Failed(Some(err));
+                return;
+            }
+        };
+        match self {
+            Walker::Collecting(collector) => {
+                if let Some(event) = event {
+                    collector.loaded.insert(id, event);
+                }
+            }
+            Walker::Ordered(_) | Walker::Failed(_) => {}
+            Walker::Lazy(frontier) => frontier.loaded(id, event),
+        }
+    }
+}
+
+// Loads the events within a walk's limits a level at a time.
+struct Collector {
+    walk: Walk,
+    loaded: HashMap<[u8; 32], Event>,
+    level: Vec<[u8; 32]>,
+    next: Vec<[u8; 32]>,
+    queue: Vec<[u8; 32]>,
+    depth: usize,
+}
+
+impl Collector {
+    // The next event to load, or `None` once the walk has everything.
+    fn step(&mut self) -> Option<[u8; 32]> {
+        if let Some(id) = self.queue.pop() {
+            return Some(id);
+        }
+        if !self.next.is_empty() {
+            let loaded = &self.loaded;
+            self.level = std::mem::take(&mut self.next)
+                .into_iter()
+                .filter(|id| loaded.contains_key(id))
+                .collect();
+            self.depth += 1;
+        }
+        self.next = self.walk.next_level(self.depth, &self.loaded, &self.level);
+        self.queue = self.next.iter().rev().cloned().collect();
+        self.queue.pop()
+    }
+}
+
+// The events a walk without limits has loaded but not yielded, and what each
+// of them still waits on.
+struct Frontier {
+    traversal: Traversal,
+    events: HashMap<[u8; 32], Event>,
+    // Events that were yielded or could not be loaded.
+    done: HashSet<[u8; 32]>,
+    requested: HashSet<[u8; 32]>,
+    // How many events each one waits on: its loaded children not yet yielded
+    // when walking newest first, its parents not yet yielded otherwise.
+    waiting: HashMap<[u8; 32], usize>,
+    // Walking parents first, the loaded children waiting on each event.
+    unblocks: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    ready: BinaryHeap<(DateTime<Utc>, [u8; 32])>,
+    oldest: BinaryHeap<Reverse<(DateTime<Utc>, [u8; 32])>>,
+    to_load: Vec<[u8; 32]>,
+}
+
+impl Frontier {
+    fn new(traversal: Traversal, heads: Vec<([u8; 32], Event)>) -> Self {
+        let mut frontier = Frontier {
+            traversal,
+            events: HashMap::new(),
+            done: HashSet::new(),
+            requested: HashSet::new(),
+            waiting: HashMap::new(),
+            unblocks: HashMap::new(),
+            ready: BinaryHeap::new(),
+            oldest: BinaryHeap::new(),
+            to_load: Vec::new(),
+        };
+        // Heads are taken in id order, so the order they were given in does
+        // not change the walk.
+        let mut ids: Vec<_> = heads.iter().map(|(id, _)| *id).collect();
+        ids.sort();
+        for (id, event) in heads {
+            frontier.requested.insert(id);
+            frontier.events.insert(id, event);
+        }
+        // Every head must be in before any is registered, so that heads
+        // which are ancestors of others wait for them.
+        for id in ids.iter() {
+            frontier.register(id);
+        }
+        for id in ids.iter() {
+            frontier.wake(id);
+        }
+        frontier
+    }
+
+    fn register(&mut self, id: &[u8; 32]) {
+        let parents = self.events[id].parents().to_vec();
+        for parent in parents {
+            if self.done.contains(&parent) {
+                continue;
+            }
+            match self.traversal {
+                Traversal::Newest => *self.waiting.entry(parent).or_default() += 1,
+                Traversal::Topological => {
+                    *self.waiting.entry(*id).or_default() += 1;
+                    self.unblocks.entry(parent).or_default().push(*id);
+                    self.request(parent);
+                }
+            }
+        }
+    }
+
+    fn request(&mut self, id: [u8; 32]) {
+        if self.requested.insert(id) {
+            self.to_load.push(id);
+        }
+    }
+
+    // Marks a loaded event ready if it no longer waits on anything.
+    fn wake(&mut self, id: &[u8; 32]) {
+        if self.waiting.get(id).copied().unwrap_or(0) > 0 {
+            return;
+        }
+        if let Some(event) = self.events.get(id) {
+            match self.traversal {
+                Traversal::Newest => self.ready.push((event.at, *id)),
+                Traversal::Topological => self.oldest.push(Reverse((event.at, *id))),
+            }
+        }
+    }
+
+    // Lets the children waiting on `id` go, once it is out or missing.
+    fn release(&mut self, id: &[u8; 32]) {
+        for child in self.unblocks.remove(id).unwrap_or_default() {
+            if let Some(count) = self.waiting.get_mut(&child) {
+                *count -= 1;
+            }
+            self.wake(&child);
+        }
+    }
+
+    fn loaded(&mut self, id: [u8; 32], event: Option<Event>) {
+        match event {
+            Some(event) => {
+                self.events.insert(id, event);
+                self.register(&id);
+                self.wake(&id);
+            }
+            None => {
+                self.done.insert(id);
+                self.release(&id);
+            }
+        }
+    }
+
+    fn pop_ready(&mut self) -> Option<[u8; 32]> {
+        match self.traversal {
+            Traversal::Newest => self.ready.pop().map(|(_, id)| id),
+            Traversal::Topological => self.oldest.pop().map(|Reverse((_, id))| id),
+        }
+    }
+
+    fn step(&mut self) -> Step {
+        loop {
+            // Newest first, parents are loaded before choosing the next event
+            // so that they compete with what is already ready. Parents first,
+            // anything ready goes out before more is loaded.
+            if self.traversal == Traversal::Newest {
+                if let Some(id) = self.to_load.pop() {
+                    return Step::Load(id);
+                }
+            }
+            let id = match self.pop_ready() {
+                Some(id) => id,
+                None => match self.to_load.pop() {
+                    Some(id) => return Step::Load(id),
+                    None => return Step::Done,
+                },
+            };
+            // An event may have been queued before a child of it was loaded.
+            if self.waiting.get(&id).copied().unwrap_or(0) > 0 {
+                continue;
+            }
+            let event = match self.events.remove(&id) {
+                Some(event) => event,
+                None => continue,
+            };
+            self.done.insert(id);
+            match self.traversal {
+                Traversal::Newest => {
+                    for parent in event.parents() {
+                        if self.done.contains(parent) {
+                            continue;
+                        }
+                        let count = self.waiting.entry(*parent).or_default();
+                        *count = count.saturating_sub(1);
+                        if *count == 0 {
+                            if self.events.contains_key(parent) {
+                                self.wake(parent);
+                            } else {
+                                self.request(*parent);
+                            }
+                        }
+                    }
+                }
+                Traversal::Topological => self.release(&id),
+            }
+            return Step::Yield(id, event);
+        }
+    }
+}
+
+pub(crate) fn decode_head(
+    id: &[u8; 32],
+    envelope: Option<Envelope<Vec<u8>>>,
+) -> anyhow::Result<Event> {
+    match envelope {
+        Some(Envelope::Event(bytes)) => Event::from_bytes(bytes),
+        Some(other) => bail!("expected an event at {}, got a {}", hex::encode(id), other),
+        None => bail!("could not find event {}", hex::encode(id)),
+    }
+}
+
+// Decodes a parent the walk asked for. A parent missing from the store is
+// `None`, to be skipped; one that could not be read or is not an event is an
+// error.
+fn decode_parent(
+    id: &[u8; 32],
+    envelope: anyhow::Result<Option<Envelope<Vec<u8>>>>,
+) -> anyhow::Result<Option<Event>> {
+    match envelope? {
+        None => Ok(None),
+        envelope => decode_head(id, envelope).map(Some),
+    }
+}
+
+// Walks the events behind one or more heads. Heads must be events in the
+// store; parents missing from it are skipped, along with their own
+// ancestors, but a parent that cannot be read or decoded ends the walk with
+// an error. By default every ancestor is yielded, newest first.
 pub struct EventIterator<'a, R: ReadableStore> {
     store: &'a R,
-    seen: HashSet<[u8; 32]>,
-    target: BinaryHeap<IdEvent>
+    heads: Vec<[u8; 32]>,
+    loaded: HashMap<[u8; 32], Event>,
+    walk: Walk,
+    walker: Option<Walker>,
+}
+
+impl<'a, R: ReadableStore> EventIterator<'a, R> {
+    pub fn new(store: &'a R, heads: &[[u8; 32]]) -> anyhow::Result<Self> {
+        let mut loaded = HashMap::new();
+        for head in heads {
+            if !loaded.contains_key(head) {
+                loaded.insert(*head, decode_head(head, store.get_sync(head)?)?);
+            }
+        }
+        Ok(EventIterator {
+            store,
+            heads: heads.to_vec(),
+            loaded,
+            walk: Walk::new(),
+            walker: None,
+        })
+    }
+
+    pub fn from_head(store: &'a R, head: &[u8; 32]) -> anyhow::Result<Self> {
+        Self::new(store, &[*head])
+    }
+
+    pub fn topological(mut self) -> Self {
+        self.walk.traversal = Traversal::Topological;
+        self
+    }
+
+    // Only follows parents up to `depth` hops from a head; 0 yields just the
+    // heads.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.walk.max_depth = Some(depth);
+        self
+    }
+
+    // Yields at most `count` events, preferring those closest to the heads.
+    pub fn max_events(mut self, count: usize) -> Self {
+        self.walk.max_events = Some(count);
+        self
+    }
 }
 
 impl<'a, R: ReadableStore> Iterator for EventIterator<'a, R> {
-    type Item = ([u8; 32], Event);
+    type Item = anyhow::Result<([u8; 32], Event)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let newest = self.target.pop()?;
-
-        let seen = &mut self.seen;
-        let store = &self.store;
-        let parents = newest.1.parents.iter().filter_map(|id| {
-            if seen.contains(id) {
-                return None
+        if self.walker.is_none() {
+            let mut loaded = std::mem::take(&mut self.loaded);
+            let heads = self
+                .walk
+                .heads(&self.heads)
+                .into_iter()
+                .filter_map(|id| Some((id, loaded.remove(&id)?)))
+                .collect();
+            self.walker = Some(Walker::new(self.walk, heads));
+        }
+        let walker = self.walker.as_mut()?;
+        loop {
+            match walker.step() {
+                Step::Yield(id, event) => return Some(Ok((id, event))),
+                Step::Load(id) => walker.loaded(id, decode_parent(&id, self.store.get_sync(id))),
+                Step::Fail(err) => return Some(Err(err)),
+                Step::Done => return None,
             }
+        }
+    }
+}
 
-            if let Envelope::Event(bytes) = store.get_sync(&id).ok()?? {
-                seen.insert(id.clone());
-                let ev = Event::from_bytes(bytes).ok()?;
-                return Some(IdEvent(id.clone(), ev))
-            } else {
-                return None
-            }
-        });
+type LoadedHeads = Vec<([u8; 32], Event)>;
 
-        for parent in parents {
-            self.target.push(parent);
+async fn load_heads<R: ReadableStore + Sync>(
+    store: &R,
+    heads: Vec<[u8; 32]>,
+) -> anyhow::Result<LoadedHeads> {
+    let mut loaded = Vec::with_capacity(heads.len());
+    for head in heads {
+        let event = decode_head(&head, store.get(head).await?)?;
+        loaded.push((head, event));
+    }
+    Ok(loaded)
+}
+
+enum StreamState<'a> {
+    Idle,
+    LoadingHeads(BoxFuture<'a, anyhow::Result<LoadedHeads>>),
+    Walking(Walker),
+    Fetching(Walker, [u8; 32], BoxFuture<'a, anyhow::Result<Option<Event>>>),
+    Done,
+}
+
+// `EventIterator` for async callers, loading through `ReadableStore::get`.
+// Nothing is read until the stream is first polled, so a missing head shows
+// up as its first item.
+pub struct EventStream<'a, R: ReadableStore> {
+    store: &'a R,
+    heads: Vec<[u8; 32]>,
+    walk: Walk,
+    state: StreamState<'a>,
+}
+
+impl<'a, R: ReadableStore + Sync> EventStream<'a, R> {
+    pub fn new(store: &'a R, heads: &[[u8; 32]]) -> Self {
+        EventStream {
+            store,
+            heads: heads.to_vec(),
+            walk: Walk::new(),
+            state: StreamState::Idle,
         }
+    }
+
+    pub fn from_head(store: &'a R, head: &[u8; 32]) -> Self {
+        Self::new(store, &[*head])
+    }
+
+    pub fn topological(mut self) -> Self {
+        self.walk.traversal = Traversal::Topological;
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.walk.max_depth = Some(depth);
+        self
+    }
+
+    pub fn max_events(mut self, count: usize) -> Self {
+        self.walk.max_events = Some(count);
+        self
+    }
+}
 
-        Some((newest.0, newest.1))
+impl<'a, R: ReadableStore + Sync> Stream for EventStream<'a, R> {
+    type Item = anyhow::Result<([u8; 32], Event)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match std::mem::replace(&mut this.state, StreamState::Done) {
+                StreamState::Idle => {
+                    let heads = this.walk.heads(&this.heads);
+                    this.state = StreamState::LoadingHeads(load_heads(this.store, heads).boxed());
+                }
+                StreamState::LoadingHeads(mut future) => match future.poll_unpin(cx) {
+                    Poll::Pending => {
+                        this.state = StreamState::LoadingHeads(future);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Ok(heads)) => {
+                        this.state = StreamState::Walking(Walker::new(this.walk, heads))
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                },
+                StreamState::Walking(mut walker) => match walker.step() {
+                    Step::Yield(id, event) => {
+                        this.state = StreamState::Walking(walker);
+                        return Poll::Ready(Some(Ok((id, event))));
+                    }
+                    Step::Load(id) => {
+                        let store = this.store;
+                        let future = async move { decode_parent(&id, store.get(id).await) };
+                        this.state = StreamState::Fetching(walker, id, future.boxed());
+                    }
+                    Step::Fail(err) => return Poll::Ready(Some(Err(err))),
+                    Step::Done => return Poll::Ready(None),
+                },
+                StreamState::Fetching(mut walker, id, mut future) => {
+                    match future.poll_unpin(cx) {
+                        Poll::Pending => {
+                            this.state = StreamState::Fetching(walker, id, future);
+                            return Poll::Pending;
+                        }
+                        Poll::Ready(event) => {
+                            walker.loaded(id, event);
+                            this.state = StreamState::Walking(walker);
+                        }
+                    }
+                }
+                StreamState::Done => return Poll::Ready(None),
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::stream::StreamExt;
+    use crate::stores::loose::LooseStore;
+    use crate::stores::multiple::FusedEnvelopeStream;
+    use crate::stores::WritableStore;
+    use crate::testing::scratch_store;
+    use async_trait::async_trait;
+    use sha2::Sha256;
     use sodiumoxide::crypto::sign;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    #[test]
-    fn varint_roundtrip_works() {
-        let mut v = Vec::new();
-        let expect = 0x80808080u64;
-        write_varint(&mut v, expect).expect("failed to write_varint");
-        let mut cursor = Cursor::new(&v[..]);
-        let result = read_varint(&mut cursor).expect("failed to read_varint");
-        assert!(expect == result);
+    // Counts the reads a walk makes.
+    struct Counting<'a>(&'a LooseStore<Sha256>, AtomicUsize);
+
+    #[async_trait]
+    impl<'a> ReadableStore for Counting<'a> {
+        type EnvelopeStream = FusedEnvelopeStream;
+
+        fn get_sync<T: AsRef<[u8]> + Send + Sync>(
+            &self,
+            item: T,
+        ) -> anyhow::Result<Option<Envelope<Vec<u8>>>> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.get_sync(item)
+        }
+
+        async fn get<T: AsRef<[u8]> + Send + Sync>(
+            &self,
+            item: T,
+        ) -> anyhow::Result<Option<Envelope<Vec<u8>>>> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.get(item).await
+        }
+
+        async fn list(&self) -> Self::EnvelopeStream {
+            unimplemented!()
+        }
+
+        async fn get_stream<'b, T: AsRef<[u8]> + Send, S: Stream<Item = &'b [u8]>>(
+            &self,
+            _item: T,
+        ) -> Option<S> {
+            unimplemented!()
+        }
+    }
+
+    async fn add(store: &LooseStore<Sha256>, event: Event) -> [u8; 32] {
+        let mut buf = Vec::new();
+        event.to_bytes(&mut buf).expect("failed to encode");
+        let envelope = Envelope::Event(buf);
+        let (id, _) = envelope.content_address::<Sha256>();
+        store.add(envelope).await.expect("failed to add");
+        id
+    }
+
+    fn rejection(
+        builder: EventBuilder,
+        sk: &SecretKey,
+        store: &LooseStore<Sha256>,
+    ) -> EventBuilderError {
+        builder
+            .sign("test", sk, store)
+            .expect_err("expected the event to be rejected")
+            .downcast()
+            .expect("expected an EventBuilderError")
     }
 
     #[test]
@@ -486,6 +1355,10 @@ mod tests {
         let (pk, sk) = sign::gen_keypair();
         let ev = EventBuilder::new()
             .at(Local.from_local_datetime(&NaiveDate::from_ymd(2013, 10, 18).and_hms(17, 0, 0)).unwrap())
+            .claim(Claim::AuthorityAdd {
+                public_key: hex::encode(pk),
+                name: "Chris Dickinson <chris@neversaw.us>".to_string(),
+            })
             .sign("Chris Dickinson <chris@neversaw.us>", &sk, &())
             .expect("failed to sign");
 
@@ -505,4 +1378,527 @@ mod tests {
             .verify(&pk)
             .expect("Failed to serialize 'ev2' in order to verify"));
     }
+
+    #[async_std::test]
+    async fn sign_checks_claims_against_history() {
+        let store = scratch_store("event-sign");
+        let (pk, sk) = sign::gen_keypair();
+        let (_, stranger) = sign::gen_keypair();
+
+        let root = EventBuilder::new()
+            .claim(Claim::AuthorityAdd {
+                public_key: hex::encode(pk),
+                name: "test".to_string(),
+            })
+            .claim(Claim::Publication {
+                version: "1.0.0".to_string(),
+                id: vec![0; 32],
+            })
+            .claim(Claim::Tag {
+                tag: "latest".to_string(),
+                version: "1.0.0".to_string(),
+            })
+            .sign("test", &sk, &store)
+            .expect("failed to sign root");
+        let root = add(&store, root).await;
+
+        // A genesis event must make its own signatory an authority.
+        let err = rejection(EventBuilder::new(), &sk, &store);
+        assert!(matches!(err, EventBuilderError::NotSelfAuthorising(name) if name == "test"));
+        let borrowed = EventBuilder::new().claim(Claim::AuthorityAdd {
+            public_key: hex::encode(pk),
+            name: "test".to_string(),
+        });
+        let err = rejection(borrowed, &stranger, &store);
+        assert!(matches!(err, EventBuilderError::NotSelfAuthorising(_)));
+
+        let err = rejection(EventBuilder::new().parent(root), &stranger, &store);
+        assert!(matches!(err, EventBuilderError::NotAuthoritative));
+
+        let err = rejection(
+            EventBuilder::new().parent(root).claim(Claim::AuthorityRemove {
+                name: "nobody".to_string(),
+            }),
+            &sk,
+            &store,
+        );
+        assert!(matches!(
+            err,
+            EventBuilderError::RemovedAuthorityDoesNotExist(name) if name == "nobody"
+        ));
+
+        let err = rejection(
+            EventBuilder::new().parent(root).claim(Claim::AuthorityAdd {
+                public_key: hex::encode(sign::gen_keypair().0),
+                name: "test".to_string(),
+            }),
+            &sk,
+            &store,
+        );
+        assert!(matches!(err, EventBuilderError::AuthorityNameConflict(name) if name == "test"));
+
+        let err = rejection(
+            EventBuilder::new().parent(root).claim(Claim::Yank {
+                version: "2.0.0".to_string(),
+                reason: "".to_string(),
+            }),
+            &sk,
+            &store,
+        );
+        assert!(matches!(err, EventBuilderError::YankedVersionDoesNotExist(v) if v == "2.0.0"));
+
+        let err = rejection(
+            EventBuilder::new().parent(root).claim(Claim::Unyank {
+                version: "1.0.0".to_string(),
+            }),
+            &sk,
+            &store,
+        );
+        assert!(matches!(err, EventBuilderError::UnyankedVersionNotYanked(v) if v == "1.0.0"));
+
+        let err = rejection(
+            EventBuilder::new().parent(root).claim(Claim::Tag {
+                tag: "next".to_string(),
+                version: "2.0.0".to_string(),
+            }),
+            &sk,
+            &store,
+        );
+        assert!(matches!(err, EventBuilderError::TaggedVersionDoesNotExist(v) if v == "2.0.0"));
+
+        let yank = EventBuilder::new()
+            .parent(root)
+            .claim(Claim::Yank {
+                version: "1.0.0".to_string(),
+                reason: "broken".to_string(),
+            })
+            .sign("test", &sk, &store)
+            .expect("failed to sign yank");
+        let yank = add(&store, yank).await;
+        EventBuilder::new()
+            .parent(yank)
+            .claim(Claim::Unyank {
+                version: "1.0.0".to_string(),
+            })
+            .sign("test", &sk, &store)
+            .expect("failed to sign unyank");
+    }
+
+    #[test]
+    fn event_encoding_is_versioned() {
+        let (pk, sk) = sign::gen_keypair();
+        let ev = EventBuilder::new()
+            .at(Utc.timestamp_opt(1_000_000_000, 0).unwrap())
+            .claim(Claim::AuthorityAdd {
+                public_key: hex::encode(pk),
+                name: "test".to_string(),
+            })
+            .sign("test", &sk, &())
+            .expect("failed to sign");
+        let mut buf = Vec::new();
+        ev.to_bytes(&mut buf).unwrap();
+        assert_eq!(&buf[..2], &[VERSIONED_EVENT, FORMAT_VERSION]);
+        let decoded = Event::from_bytes(&buf[..]).expect("failed to decode");
+        assert_eq!(decoded.algorithm(), SignatureAlgorithm::Ed25519);
+        assert!(decoded.verify(&pk).unwrap());
+        assert_eq!(decoded, ev);
+
+        let mut unknown = buf.clone();
+        unknown[1] = 0x7f;
+        assert!(Event::from_bytes(&unknown[..]).is_err());
+        assert!(Event::from_bytes(&buf[..buf.len() - 10]).is_err());
+
+        // Events written before the format was versioned.
+        let mut legacy = decoded;
+        legacy.format = LEGACY_FORMAT;
+        let mut unsigned = Vec::new();
+        legacy.to_bytes_unsigned(&mut unsigned).unwrap();
+        legacy.signature = sign_detached(&unsigned[..], &sk).to_bytes().to_vec();
+        let mut buf = Vec::new();
+        legacy.to_bytes(&mut buf).unwrap();
+        assert_eq!(buf[0], legacy.claimset);
+        let decoded = Event::from_bytes(&buf[..]).expect("failed to decode legacy event");
+        assert_eq!(decoded, legacy);
+        assert!(decoded.verify(&pk).unwrap());
+
+        let truncated = Event::from_bytes(&buf[..buf.len() - 10]).expect("failed to decode");
+        assert!(truncated.verify(&pk).is_err());
+    }
+
+    fn random_bytes(rng: &mut fastrand::Rng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| rng.u8(..)).collect()
+    }
+
+    fn random_string(rng: &mut fastrand::Rng) -> String {
+        (0..rng.usize(0..12))
+            .map(|_| rng.char('\u{20}'..='\u{2ff}'))
+            .collect()
+    }
+
+    fn random_claim(rng: &mut fastrand::Rng) -> Claim {
+        match rng.u8(0..7) {
+            0 => Claim::AuthorityAdd {
+                public_key: random_string(rng),
+                name: random_string(rng),
+            },
+            1 => Claim::AuthorityRemove {
+                name: random_string(rng),
+            },
+            2 => Claim::Yank {
+                version: random_string(rng),
+                reason: random_string(rng),
+            },
+            3 => Claim::Unyank {
+                version: random_string(rng),
+            },
+            4 => Claim::Tag {
+                tag: random_string(rng),
+                version: random_string(rng),
+            },
+            5 => Claim::Publication {
+                version: random_string(rng),
+                id: random_bytes(rng, 32),
+            },
+            _ => {
+                let mut typeno = rng.u64(..);
+                while KNOWN_CLAIM_TYPES.contains(&typeno) {
+                    typeno = rng.u64(..);
+                }
+                let len = rng.usize(0..16);
+                Claim::Other {
+                    typeno,
+                    data: random_bytes(rng, len),
+                }
+            }
+        }
+    }
+
+    fn random_event(rng: &mut fastrand::Rng) -> Event {
+        let claims: Vec<_> = (0..rng.usize(0..6)).map(|_| random_claim(rng)).collect();
+        let mut parents: Vec<[u8; 32]> = (0..rng.usize(0..4)).map(|_| [rng.u8(..); 32]).collect();
+        parents.sort();
+        parents.dedup();
+        let (format, nanos) = match rng.bool() {
+            true => (FORMAT_VERSION, rng.u32(0..1_000_000_000)),
+            false => (SECONDS_FORMAT, 0),
+        };
+        Event {
+            format,
+            algorithm: SignatureAlgorithm::Ed25519,
+            claimset: claims.iter().fold(0, |mask, claim| mask | claim.bitmask()),
+            at: Utc.timestamp_opt(rng.i64(0..4_000_000_000), nanos).unwrap(),
+            claims,
+            parents,
+            signatory: random_string(rng),
+            signature: random_bytes(rng, 64),
+        }
+    }
+
+    #[test]
+    fn codec_roundtrips_random_events() {
+        let mut rng = fastrand::Rng::with_seed(0x0e05);
+        for _ in 0..256 {
+            let event = random_event(&mut rng);
+            let mut buf = Vec::new();
+            event.to_bytes(&mut buf).expect("failed to encode");
+            let decoded = Event::from_bytes(&buf[..]).expect("failed to decode");
+            assert_eq!(decoded, event);
+
+            // Whatever still decodes after damage must be the one encoding
+            // of the event it decodes to.
+            for _ in 0..16 {
+                let mut damaged = buf.clone();
+                match rng.u8(0..3) {
+                    0 => damaged.truncate(rng.usize(0..buf.len())),
+                    1 => damaged[rng.usize(0..buf.len())] ^= 1 << rng.u8(0..8),
+                    _ => damaged.insert(rng.usize(0..=buf.len()), rng.u8(..)),
+                }
+                if let Ok(decoded) = Event::from_bytes(&damaged[..]) {
+                    let mut again = Vec::new();
+                    decoded.to_bytes(&mut again).expect("failed to encode");
+                    assert_eq!(again, damaged);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn codec_rejects_non_canonical_events() {
+        let mut rng = fastrand::Rng::with_seed(0x0e06);
+        let mut event = random_event(&mut rng);
+        event.claims = vec![Claim::Unyank {
+            version: "1.0.0".to_string(),
+        }];
+        event.claimset = 0x10;
+        event.parents = vec![[1; 32], [2; 32]];
+        let mut buf = Vec::new();
+        event.to_bytes(&mut buf).unwrap();
+        assert!(Event::from_bytes(&buf[..]).is_ok());
+
+        let mut trailing = buf.clone();
+        trailing.push(0);
+        assert!(Event::from_bytes(&trailing[..]).is_err());
+
+        event.claimset = 0x30;
+        let mut buf = Vec::new();
+        event.to_bytes(&mut buf).unwrap();
+        assert!(Event::from_bytes(&buf[..]).is_err());
+
+        event.claimset = 0x10;
+        event.parents.reverse();
+        let mut buf = Vec::new();
+        event.to_bytes(&mut buf).unwrap();
+        assert!(Event::from_bytes(&buf[..]).is_err());
+
+        let mut claim = Vec::new();
+        event.claims[0].to_bytes(&mut claim).unwrap();
+        claim.push(0);
+        assert!(Claim::from_bytes(&claim[..]).is_err());
+        assert!(Claim::Other {
+            typeno: 0x20,
+            data: vec![],
+        }
+        .to_bytes(&mut Vec::new())
+        .is_err());
+    }
+
+    #[test]
+    fn event_hash_is_stable() {
+        let (pk, sk) = sign::keypair_from_seed(&sign::Seed([7; 32]));
+        let mut event = Event {
+            format: SECONDS_FORMAT,
+            algorithm: SignatureAlgorithm::Ed25519,
+            claimset: 0x61,
+            at: Utc.timestamp_opt(1_500_000_000, 0).unwrap(),
+            claims: vec![
+                Claim::AuthorityAdd {
+                    public_key: hex::encode(pk),
+                    name: "test".to_string(),
+                },
+                Claim::Publication {
+                    version: "1.0.0".to_string(),
+                    id: vec![1; 32],
+                },
+                Claim::Tag {
+                    tag: "latest".to_string(),
+                    version: "1.0.0".to_string(),
+                },
+            ],
+            parents: vec![[2; 32], [3; 32]],
+            signatory: "test".to_string(),
+            signature: Vec::new(),
+        };
+        let mut unsigned = Vec::new();
+        event.to_bytes_unsigned(&mut unsigned).unwrap();
+        event.signature = sign_detached(&unsigned[..], &sk).to_bytes().to_vec();
+
+        let mut buf = Vec::new();
+        event.to_bytes(&mut buf).unwrap();
+        let (id, _) = Envelope::Event(&buf[..]).content_address::<Sha256>();
+        assert_eq!(
+            hex::encode(id),
+            "94f312388852c6cdce93b278159d0e79111ea50d6f5fb75ae6ec36e1dfa8a1e4"
+        );
+
+        event.format = FORMAT_VERSION;
+        event.at = Utc.timestamp_opt(1_500_000_000, 250_000_000).unwrap();
+        let mut unsigned = Vec::new();
+        event.to_bytes_unsigned(&mut unsigned).unwrap();
+        event.signature = sign_detached(&unsigned[..], &sk).to_bytes().to_vec();
+
+        let mut buf = Vec::new();
+        event.to_bytes(&mut buf).unwrap();
+        let (id, _) = Envelope::Event(&buf[..]).content_address::<Sha256>();
+        assert_eq!(
+            hex::encode(id),
+            "08825d3cef445b5c01b66d77f86e6cf0045350a99e1801e3f2f38374835d613e"
+        );
+    }
+
+    #[async_std::test]
+    async fn events_keep_subsecond_order() {
+        let store = scratch_store("event-order");
+        let (pk, sk) = sign::gen_keypair();
+        let at = |nanos| Utc.timestamp_opt(1_000_000_000, nanos).unwrap();
+
+        let root = EventBuilder::new()
+            .at(at(100))
+            .claim(Claim::AuthorityAdd {
+                public_key: hex::encode(pk),
+                name: "test".to_string(),
+            })
+            .sign("test", &sk, &store)
+            .expect("failed to sign");
+        let mut buf = Vec::new();
+        root.to_bytes(&mut buf).unwrap();
+        assert_eq!(Event::from_bytes(&buf[..]).unwrap().at, at(100));
+        let root = add(&store, root).await;
+
+        let mut children = Vec::new();
+        for tag in &["a", "b", "c"] {
+            let child = EventBuilder::new()
+                .at(at(200))
+                .parent(root)
+                .claim(Claim::Other {
+                    typeno: 0x80,
+                    data: tag.as_bytes().to_vec(),
+                })
+                .sign("test", &sk, &store)
+                .expect("failed to sign");
+            children.push(add(&store, child).await);
+        }
+        let mut merge = EventBuilder::new().at(at(300));
+        for child in children.iter() {
+            merge = merge.parent(child);
+        }
+        let merge = add(&store, merge.sign("test", &sk, &store).unwrap()).await;
+
+        // Newest first; the children share a timestamp, so the highest id
+        // comes first.
+        children.sort();
+        let mut expected = vec![merge];
+        expected.extend(children.iter().rev());
+        expected.push(root);
+        let walked: Vec<_> = EventIterator::new(&store, &[merge])
+            .unwrap()
+            .map(|item| item.expect("failed to walk").0)
+            .collect();
+        assert_eq!(walked, expected);
+    }
+
+    #[async_std::test]
+    async fn event_walks_follow_traversal_and_limits() {
+        let store = scratch_store("event-walk");
+        let (pk, sk) = sign::gen_keypair();
+        let at = |secs| Utc.timestamp_opt(secs, 0).unwrap();
+
+        // The root claims to be newer than everything after it.
+        let root = EventBuilder::new()
+            .at(at(300))
+            .claim(Claim::AuthorityAdd {
+                public_key: hex::encode(pk),
+                name: "test".to_string(),
+            })
+            .sign("test", &sk, &store)
+            .expect("failed to sign");
+        let root = add(&store, root).await;
+        let left = EventBuilder::new().at(at(100)).parent(root);
+        let left = add(&store, left.sign("test", &sk, &store).unwrap()).await;
+        let right = EventBuilder::new().at(at(150)).parent(root);
+        let right = add(&store, right.sign("test", &sk, &store).unwrap()).await;
+        let head = EventBuilder::new().at(at(200)).parent(left).parent(right);
+        let head = add(&store, head.sign("test", &sk, &store).unwrap()).await;
+
+        let ids = |walk: EventIterator<LooseStore<Sha256>>| -> Vec<_> {
+            walk.map(|item| item.expect("failed to walk").0).collect()
+        };
+        let newest = ids(EventIterator::from_head(&store, &head).unwrap());
+        assert_eq!(newest, vec![head, right, left, root]);
+        // Parents first, the walk loads the greater parent first and yields
+        // as soon as it can.
+        let topological = ids(EventIterator::from_head(&store, &head).unwrap().topological());
+        let (low, high) = (std::cmp::min(left, right), std::cmp::max(left, right));
+        assert_eq!(topological, vec![root, high, low, head]);
+
+        let shallow = ids(EventIterator::from_head(&store, &head).unwrap().max_depth(1));
+        assert_eq!(shallow, vec![head, right, left]);
+        let few = ids(EventIterator::new(&store, &[head, left]).unwrap().max_events(2));
+        assert_eq!(few, vec![head, left]);
+        let few = ids(EventIterator::from_head(&store, &head).unwrap().max_events(2));
+        assert_eq!(few, vec![head, std::cmp::min(left, right)]);
+
+        let streamed: Vec<_> = EventStream::new(&store, &[head, right])
+            .topological()
+            .map(|item| item.expect("failed to walk").0)
+            .collect()
+            .await;
+        assert_eq!((streamed[0], streamed[3]), (root, head));
+        assert_eq!(streamed[1..3].iter().min(), Some(&low));
+        assert_eq!(streamed[1..3].iter().max(), Some(&high));
+        let mut missing = EventStream::from_head(&store, &[9; 32]);
+        assert!(missing.next().await.unwrap().is_err());
+        assert!(missing.next().await.is_none());
+    }
+
+    #[async_std::test]
+    async fn event_walks_read_only_what_they_yield() {
+        let store = scratch_store("event-lazy");
+        let (pk, sk) = sign::gen_keypair();
+        let at = |secs| Utc.timestamp_opt(secs, 0).unwrap();
+
+        // A root with many children, all merged by an event that a chain of
+        // ten more follows.
+        let root = EventBuilder::new()
+            .at(at(100))
+            .claim(Claim::AuthorityAdd {
+                public_key: hex::encode(pk),
+                name: "test".to_string(),
+            })
+            .sign("test", &sk, &store)
+            .expect("failed to sign");
+        let root = add(&store, root).await;
+        let mut head = EventBuilder::new().at(at(1000));
+        for secs in 200..220 {
+            let child = EventBuilder::new().at(at(secs)).parent(root);
+            let child = add(&store, child.sign("test", &sk, &store).unwrap()).await;
+            head = head.parent(child);
+        }
+        let mut head = add(&store, head.sign("test", &sk, &store).unwrap()).await;
+        for secs in 1001..1011 {
+            let next = EventBuilder::new().at(at(secs)).parent(head);
+            head = add(&store, next.sign("test", &sk, &store).unwrap()).await;
+        }
+
+        let counting = Counting(&store, AtomicUsize::new(0));
+        let newest: Vec<_> = EventIterator::from_head(&counting, &head).unwrap().take(3).collect();
+        assert_eq!(newest.len(), 3);
+        assert_eq!(counting.1.load(Ordering::SeqCst), 3);
+
+        // Parents first, the root comes out once one path down to it is in:
+        // the chain, the merge, one child and the root.
+        let counting = Counting(&store, AtomicUsize::new(0));
+        let first: Vec<_> = EventStream::from_head(&counting, &head)
+            .topological()
+            .take(1)
+            .map(|item| item.expect("failed to walk").0)
+            .collect()
+            .await;
+        assert_eq!(first, vec![root]);
+        assert_eq!(counting.1.load(Ordering::SeqCst), 13);
+
+        let all = EventIterator::from_head(&store, &head).unwrap().topological().count();
+        assert_eq!(all, 32);
+    }
+
+    #[async_std::test]
+    async fn event_walks_fail_on_unreadable_parents() {
+        let store = scratch_store("event-unreadable");
+        let (_, sk) = sign::gen_keypair();
+
+        // A parent the store does not have is skipped.
+        let orphan = EventBuilder::new().parent([9; 32]).sign_unchecked("test", &sk);
+        let orphan = add(&store, orphan).await;
+        let walked: Vec<_> = EventIterator::from_head(&store, &orphan)
+            .unwrap()
+            .map(|item| item.expect("failed to walk").0)
+            .collect();
+        assert_eq!(walked, vec![orphan]);
+
+        // One that is there but is not an event ends the walk.
+        let blob = Envelope::Blob(b"not an event".to_vec());
+        let (blob_id, _) = blob.content_address::<Sha256>();
+        store.add(blob).await.expect("failed to add");
+        let child = EventBuilder::new().parent(blob_id).sign_unchecked("test", &sk);
+        let child = add(&store, child).await;
+
+        let mut walk = EventIterator::from_head(&store, &child).unwrap();
+        assert_eq!(walk.next().unwrap().unwrap().0, child);
+        assert!(walk.next().unwrap().is_err());
+        assert!(walk.next().is_none());
+        let mut stream = EventStream::from_head(&store, &child);
+        assert_eq!(stream.next().await.unwrap().unwrap().0, child);
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+        assert!(crate::package::history_sync(&store, &[child]).is_err());
+    }
 }
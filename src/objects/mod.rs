@@ -1,2 +1,4 @@
 pub mod event;
 pub mod blob;
+pub mod varint;
+pub mod version;
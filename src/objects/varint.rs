@@ -0,0 +1,101 @@
+use anyhow::bail;
+use std::io::{Read, Write};
+
+// The varint crate let me down. This could be better/faster.
+//
+// Only the shortest encoding of a value is accepted, so every value has
+// exactly one encoding.
+pub(crate) fn read_varint<R: Read>(r: &mut R) -> anyhow::Result<u64> {
+    let mut byt = [0u8; 1];
+    let mut shift = 0;
+    let mut accum = 0u64;
+    let mask = 0x7fu64;
+    while {
+        r.read_exact(&mut byt)?;
+
+        let item = byt[0] as u64;
+        if shift == 9 && item > 1 {
+            bail!("varint overflows 64 bits");
+        }
+        if shift > 0 && item == 0 {
+            bail!("varint is not minimally encoded");
+        }
+        accum |= (item & mask) << (shift * 7);
+        shift += 1;
+        item & 0x80 != 0
+    } {}
+    Ok(accum)
+}
+
+// Reads a length-prefixed byte string. The buffer grows with what is read
+// rather than with the length claimed, so a corrupt length cannot exhaust
+// memory.
+pub(crate) fn read_varint_bytes<R: Read>(r: &mut R) -> anyhow::Result<Vec<u8>> {
+    let len: u64 = read_varint(r)?;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        bail!("EOF while reading {} bytes", len);
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn read_varint_string<R: Read>(r: &mut R) -> anyhow::Result<String> {
+    Ok(String::from_utf8(read_varint_bytes(r)?)?)
+}
+
+pub(crate) fn write_varint<W: Write, I: Into<u64>>(w: &mut W, input: I) -> anyhow::Result<usize> {
+    const MSB_ALL: u64 = !0x7fu64;
+    let mut input_u64: u64 = input.into();
+    let mut bytes: Vec<u8> = Vec::with_capacity(8);
+    while input_u64 & MSB_ALL > 0 {
+        bytes.push(((input_u64 & 0xFF) as u8) | 0x80u8);
+        input_u64 >>= 7;
+    }
+    bytes.push((input_u64 & 0x7F) as u8);
+    w.write_all(&bytes[..])?;
+    Ok(bytes.len())
+}
+
+pub(crate) fn write_varint_str<W: Write>(w: &mut W, s: &str) -> anyhow::Result<usize> {
+    let bytes = s.as_bytes();
+    let written = write_varint(w, bytes.len() as u64)?;
+    w.write_all(bytes)?;
+    Ok(written + bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn varint_roundtrip_works() {
+        let mut v = Vec::new();
+        let expect = 0x80808080u64;
+        write_varint(&mut v, expect).expect("failed to write_varint");
+        let mut cursor = Cursor::new(&v[..]);
+        let result = read_varint(&mut cursor).expect("failed to read_varint");
+        assert!(expect == result);
+    }
+
+    #[test]
+    fn varint_rejects_overlong_encodings() {
+        for (bytes, value) in &[
+            (&[0x00][..], Some(0)),
+            (&[0x80, 0x01][..], Some(0x80)),
+            (&[0x81, 0x00][..], None),
+            (&[0x80, 0x80, 0x00][..], None),
+            (&[0xff; 9][..], None),
+        ] {
+            let mut cursor = Cursor::new(*bytes);
+            assert_eq!(read_varint(&mut cursor).ok(), *value, "{:?}", bytes);
+        }
+
+        let mut max = Vec::new();
+        write_varint(&mut max, u64::MAX).unwrap();
+        assert_eq!(read_varint(&mut Cursor::new(&max[..])).unwrap(), u64::MAX);
+        *max.last_mut().unwrap() = 0x02;
+        assert!(read_varint(&mut Cursor::new(&max[..])).is_err());
+    }
+}
@@ -1,6 +1,167 @@
+use crate::envelope::Envelope;
+use crate::objects::varint::{read_varint, read_varint_string, write_varint, write_varint_str};
+use crate::stores::extracted::ExtractedBlobs;
 use crate::stores::ReadableStore;
+use anyhow::bail;
+use async_std::fs as afs;
+use chrono::prelude::*;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use sha2::Digest;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 
+// How a path is installed. A symlink's blob holds its target rather than
+// file contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Mode {
+    Regular,
+    Executable,
+    Symlink,
+}
+
+impl Mode {
+    fn from_byte(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            0 => Ok(Mode::Regular),
+            1 => Ok(Mode::Executable),
+            2 => Ok(Mode::Symlink),
+            _ => bail!("unknown path mode {}", byte),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Mode::Regular => 0,
+            Mode::Executable => 1,
+            Mode::Symlink => 2,
+        }
+    }
+}
+
+// Which manifest field a dependency came from. The order here is the order
+// a package's entries for one name are encoded in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DependencyKind {
+    Regular,
+    Dev,
+    Peer,
+    Optional,
+}
+
+impl DependencyKind {
+    fn from_byte(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            0 => Ok(DependencyKind::Regular),
+            1 => Ok(DependencyKind::Dev),
+            2 => Ok(DependencyKind::Peer),
+            3 => Ok(DependencyKind::Optional),
+            _ => bail!("unknown dependency kind {}", byte),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            DependencyKind::Regular => 0,
+            DependencyKind::Dev => 1,
+            DependencyKind::Peer => 2,
+            DependencyKind::Optional => 3,
+        }
+    }
+
+    // The package.json field each kind is read from.
+    pub fn field(self) -> &'static str {
+        match self {
+            DependencyKind::Regular => "dependencies",
+            DependencyKind::Dev => "devDependencies",
+            DependencyKind::Peer => "peerDependencies",
+            DependencyKind::Optional => "optionalDependencies",
+        }
+    }
+}
+
+const DEPENDENCY_KINDS: [DependencyKind; 4] = [
+    DependencyKind::Regular,
+    DependencyKind::Dev,
+    DependencyKind::Peer,
+    DependencyKind::Optional,
+];
+
+// Reasons a version's file map is unsafe to unpack.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PathError {
+    #[error("path {0:?} is empty or has an empty component")]
+    Empty(String),
+    #[error("path {0:?} is absolute")]
+    Absolute(String),
+    #[error("path {0:?} has a \".\" or \"..\" component")]
+    Traversal(String),
+    #[error("path {0:?} contains a backslash or NUL")]
+    InvalidCharacter(String),
+    #[error("path {0:?} is not in Unicode NFC form")]
+    NotNormalized(String),
+    #[error("path {1:?} does not sort after {0:?}")]
+    Unsorted(String, String),
+    #[error("path {1:?} lies beneath the file {0:?}")]
+    BeneathFile(String, String),
+    #[error("paths {0:?} and {1:?} collide on case-insensitive filesystems")]
+    CaseCollision(String, String),
+    #[error("symlink {0:?} points to {1:?}, outside the package")]
+    LinkEscapes(String, String),
+}
+
+pub fn check_path(path: &str) -> Result<(), PathError> {
+    if path.starts_with('/') {
+        return Err(PathError::Absolute(path.to_string()));
+    }
+    if path.contains(['\\', '\0']) {
+        return Err(PathError::InvalidCharacter(path.to_string()));
+    }
+    for component in path.split('/') {
+        match component {
+            "" => return Err(PathError::Empty(path.to_string())),
+            "." | ".." => return Err(PathError::Traversal(path.to_string())),
+            _ => {}
+        }
+    }
+    if !is_nfc(path) {
+        return Err(PathError::NotNormalized(path.to_string()));
+    }
+    Ok(())
+}
+
+// Checks that a symlink at `link`, an entry path, points inside the package.
+// The target is read relative to the link's directory: it must be relative,
+// and ".." may only lead it, climbing no higher than the package root.
+// Because entries never lie beneath a file or symlink, those leading ".."
+// cross real directories, and the rest of the target only descends.
+pub fn check_link(link: &str, target: &str) -> Result<(), PathError> {
+    let escapes = || PathError::LinkEscapes(link.to_string(), target.to_string());
+    if target.is_empty() || target.starts_with('/') || target.contains(['\\', '\0']) {
+        return Err(escapes());
+    }
+
+    let mut depth = link.matches('/').count();
+    let mut descending = false;
+    for component in target.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if descending || depth == 0 => return Err(escapes()),
+            ".." => depth -= 1,
+            _ => descending = true,
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Version {
+    name: String,
+    version: String,
+    created: DateTime<Utc>,
+    dependencies: Vec<(String, DependencyKind, String)>,
     // NB: Why not a HashMap? We want to store these with a particular
     // order. We know that the paths will be sorted so lookup will be
     // log(N). BTreeMaps are also sorted with log(N) lookup, but
@@ -9,13 +170,784 @@ pub struct Version {
     //
     // THAT SAID. If you are interested in speeding this up, please
     // prove me wrong! <3
-    paths: Vec<(String, [u8; 32]>
+    paths: Vec<(String, Mode, [u8; 32])>,
 }
 
+// Reads `count` length-prefixed keys, each followed by a value read by
+// `read_value`, insisting that keys are strictly increasing.
+fn read_sorted<R: Read, T, F: Fn(&mut R) -> anyhow::Result<T>>(
+    cursor: &mut R,
+    what: &str,
+    read_value: F,
+) -> anyhow::Result<Vec<(String, T)>> {
+    let count = read_varint(cursor)? as usize;
+    let mut items: Vec<(String, T)> = Vec::new();
+    while items.len() < count {
+        let key = read_varint_string(cursor)?;
+        let value = read_value(cursor)?;
+        if let Some(last) = items.last() {
+            if last.0 >= key {
+                bail!("version {} are not sorted: {:?} follows {:?}", what, key, last.0);
+            }
+        }
+        items.push((key, value));
+    }
+    Ok(items)
+}
+
+// Writes a file, executable or symlink at `path`, the place on disk of the
+// version entry `entry`. Only unix filesystems can represent the latter two;
+// elsewhere executables are written as plain files and symlinks are refused.
+// A symlink whose target leaves the package is refused everywhere.
+pub(crate) fn install_sync(
+    path: &Path,
+    entry: &str,
+    mode: Mode,
+    bytes: &[u8],
+) -> anyhow::Result<()> {
+    match mode {
+        Mode::Regular => std::fs::write(path, bytes)?,
+        Mode::Executable => {
+            std::fs::write(path, bytes)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+            }
+        }
+        Mode::Symlink => {
+            let target = match std::str::from_utf8(bytes) {
+                Ok(target) => target,
+                Err(_) => bail!("symlink {:?} has a target that is not valid utf-8", entry),
+            };
+            check_link(entry, target)?;
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(target, path)?;
+            #[cfg(not(unix))]
+            bail!("cannot create symlink {:?} on this platform", path);
+        }
+    }
+    Ok(())
+}
+
+async fn install(path: PathBuf, entry: String, mode: Mode, bytes: Vec<u8>) -> anyhow::Result<()> {
+    match mode {
+        Mode::Regular => Ok(afs::write(path, bytes).await?),
+        _ => {
+            async_std::task::spawn_blocking(move || install_sync(&path, &entry, mode, &bytes[..]))
+                .await
+        }
+    }
+}
 
 impl Version {
+    pub fn from_bytes<T: AsRef<[u8]> + Send>(input: T) -> anyhow::Result<Self> {
+        // name length(varint)
+        // name
+        // version length(varint)
+        // version
+        // created(i64)
+        // dependency count(varint)
+        // dependencies * N, sorted by name, then kind
+        //      name length(varint)
+        //      name
+        //      kind(u8): 0 regular, 1 dev, 2 peer, 3 optional
+        //      range length(varint)
+        //      range
+        // path count(varint)
+        // paths * N, sorted by path
+        //      path length(varint)
+        //      path
+        //      mode(u8): 0 regular, 1 executable, 2 symlink
+        //      blob id(32 bytes)
+        let bytes = input.as_ref();
+        let mut cursor = Cursor::new(bytes);
+
+        let name = read_varint_string(&mut cursor)?;
+        let version = read_varint_string(&mut cursor)?;
+
+        let mut created_bytes = [0u8; 8];
+        cursor.read_exact(&mut created_bytes)?;
+        let created = match Utc
+            .timestamp_opt(i64::from_be_bytes(created_bytes), 0)
+            .single()
+        {
+            Some(created) => created,
+            None => bail!("version creation date is out of range"),
+        };
+
+        let dependency_count = read_varint(&mut cursor)? as usize;
+        let mut dependencies: Vec<(String, DependencyKind, String)> = Vec::new();
+        while dependencies.len() < dependency_count {
+            let name = read_varint_string(&mut cursor)?;
+            let mut kind = [0u8; 1];
+            cursor.read_exact(&mut kind)?;
+            let kind = DependencyKind::from_byte(kind[0])?;
+            let range = read_varint_string(&mut cursor)?;
+            if let Some((last_name, last_kind, _)) = dependencies.last() {
+                if (last_name, last_kind) >= (&name, &kind) {
+                    bail!("version dependencies are not sorted: {:?} follows {:?}", name, last_name);
+                }
+            }
+            dependencies.push((name, kind, range));
+        }
+        let paths: Vec<_> = read_sorted(&mut cursor, "paths", |c| {
+            let mut mode = [0u8; 1];
+            c.read_exact(&mut mode)?;
+            let mut id = [0u8; 32];
+            c.read_exact(&mut id)?;
+            Ok((Mode::from_byte(mode[0])?, id))
+        })?
+        .into_iter()
+        .map(|(path, (mode, id))| (path, mode, id))
+        .collect();
+
+        if cursor.position() as usize != bytes.len() {
+            bail!("trailing bytes after version");
+        }
+
+        let version = Version {
+            name,
+            version,
+            created,
+            dependencies,
+            paths,
+        };
+        version.check_paths()?;
+        Ok(version)
+    }
+
+    pub fn to_bytes<W: Write>(&self, destination: &mut W) -> anyhow::Result<usize> {
+        let mut written = write_varint_str(destination, &self.name)?;
+        written += write_varint_str(destination, &self.version)?;
+
+        destination.write_all(&self.created.timestamp().to_be_bytes())?;
+        written += 8;
+
+        written += write_varint(destination, self.dependencies.len() as u64)?;
+        for (name, kind, range) in self.dependencies.iter() {
+            written += write_varint_str(destination, name)?;
+            destination.write_all(&[kind.to_byte()])?;
+            written += 1;
+            written += write_varint_str(destination, range)?;
+        }
+
+        written += write_varint(destination, self.paths.len() as u64)?;
+        for (path, mode, id) in self.paths.iter() {
+            written += write_varint_str(destination, path)?;
+            destination.write_all(&[mode.to_byte()])?;
+            destination.write_all(&id[..])?;
+            written += 1 + id.len();
+        }
+        Ok(written)
+    }
+
+    pub fn from_envelope<T: AsRef<[u8]> + Send>(envelope: &Envelope<T>) -> anyhow::Result<Self> {
+        match envelope {
+            Envelope::Version(bytes) => Version::from_bytes(bytes.as_ref()),
+            _ => bail!("expected a version, got a {}", envelope),
+        }
+    }
+
+    pub fn to_envelope(&self) -> anyhow::Result<Envelope<Vec<u8>>> {
+        let mut bytes = Vec::new();
+        self.to_bytes(&mut bytes)?;
+        Ok(Envelope::Version(bytes))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn created(&self) -> &DateTime<Utc> {
+        &self.created
+    }
+
+    pub fn dependencies(&self) -> &[(String, DependencyKind, String)] {
+        &self.dependencies[..]
+    }
+
+    pub fn paths(&self) -> &[(String, Mode, [u8; 32])] {
+        &self.paths[..]
+    }
+
+    // Checks that every path stays inside the directory the version is
+    // unpacked into and names a distinct file there, even on filesystems
+    // that fold case. Paths must be relative, NFC-normalised, free of empty,
+    // "." and ".." components, strictly sorted, and must not lie beneath
+    // another entry; a file or symlink cannot double as a directory. Symlink
+    // targets live in blobs, so `check_link` checks them as they are read.
+    pub fn check_paths(&self) -> Result<(), PathError> {
+        let mut files = HashSet::new();
+        let mut folded = HashMap::new();
+        let mut last: Option<&str> = None;
+        for (path, _, _) in self.paths.iter() {
+            check_path(path)?;
+            if let Some(last) = last {
+                if last >= path.as_str() {
+                    return Err(PathError::Unsorted(last.to_string(), path.to_string()));
+                }
+            }
+            last = Some(path);
+            files.insert(path.as_str());
+            if let Some(other) = folded.insert(path.to_lowercase(), path.as_str()) {
+                return Err(PathError::CaseCollision(other.to_string(), path.to_string()));
+            }
+        }
+
+        for (path, _, _) in self.paths.iter() {
+            let mut rest = path.as_str();
+            while let Some(idx) = rest.rfind('/') {
+                rest = &rest[..idx];
+                if files.contains(rest) {
+                    return Err(PathError::BeneathFile(rest.to_string(), path.to_string()));
+                }
+                if let Some(other) = folded.get(&rest.to_lowercase()) {
+                    return Err(PathError::CaseCollision(other.to_string(), path.to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // paths -> unique dirnames -> reduced to those that aren't an ancestor
+    // of another. Creating just those with create_dir_all yields every
+    // directory the version needs.
+    fn leaf_directories(&self) -> Vec<&str> {
+        let mut dirnames = HashSet::new();
+        for (path, _, _) in self.paths.iter() {
+            if let Some(idx) = path.rfind('/') {
+                dirnames.insert(&path[..idx]);
+            }
+        }
+
+        let mut ancestors = HashSet::new();
+        for dirname in dirnames.iter() {
+            let mut rest = *dirname;
+            while let Some(idx) = rest.rfind('/') {
+                rest = &rest[..idx];
+                ancestors.insert(rest);
+            }
+        }
+
+        let mut leaves: Vec<_> = dirnames.difference(&ancestors).cloned().collect();
+        leaves.sort_unstable();
+        leaves
+    }
+
+    fn read_blob<T: AsRef<[u8]> + Send>(
+        path: &str,
+        object: Option<Envelope<T>>,
+    ) -> anyhow::Result<T> {
+        match object {
+            Some(Envelope::Blob(bytes)) => Ok(bytes),
+            Some(other) => bail!("expected a blob for {}, got a {}", path, other),
+            None => bail!("could not find the blob for {}", path),
+        }
+    }
+
+    // Unpacks the version into `destination`. Files are written into a
+    // staging directory next to `destination` that is renamed into place once
+    // complete, so a crash never leaves a half-unpacked package behind.
+    pub fn unpack_sync<P: AsRef<Path>, R: ReadableStore>(
+        &self,
+        destination: P,
+        store: &R,
+    ) -> anyhow::Result<()> {
+        self.unpack_staged_sync(destination.as_ref(), |target, path, mode, id| {
+            let bytes = Self::read_blob(path, store.get_sync(id)?)?;
+            install_sync(target, path, mode, &bytes[..])
+        })
+    }
+
+    // Like `unpack_sync`, but links files from `blobs` rather than writing
+    // fresh copies, extracting any blob that is not there yet. Installed
+    // files share the cache's read-only permissions.
+    pub fn unpack_sync_linked<P, R, D>(
+        &self,
+        destination: P,
+        store: &R,
+        blobs: &ExtractedBlobs<D>,
+    ) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        R: ReadableStore,
+        D: 'static + Digest + Send + Sync,
+    {
+        self.unpack_staged_sync(destination.as_ref(), |target, path, mode, id| match mode {
+            Mode::Symlink => {
+                let bytes = Self::read_blob(path, store.get_sync(id)?)?;
+                install_sync(target, path, mode, &bytes[..])
+            }
+            _ => blobs.install_sync(store, id, mode, target),
+        })
+    }
+
+    fn unpack_staged_sync<F>(&self, destination: &Path, install: F) -> anyhow::Result<()>
+    where
+        F: Fn(&Path, &str, Mode, &[u8; 32]) -> anyhow::Result<()>,
+    {
+        self.check_paths()?;
+        let staging = staging_path(destination)?;
+        let result = (|| {
+            clear_stale(&staging)?;
+            std::fs::create_dir_all(&staging)?;
+            for dirname in self.leaf_directories() {
+                std::fs::create_dir_all(staging.join(dirname))?;
+            }
+
+            for (path, mode, id) in self.paths.iter() {
+                install(&staging.join(path), path, *mode, id)?;
+            }
+            swap_into_place(&staging, destination)
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&staging);
+        }
+        result
+    }
+
+    // Like `unpack_sync`, but fetches and writes files concurrently.
+    pub async fn unpack<P: AsRef<Path>, R: ReadableStore + Sync>(
+        &self,
+        destination: P,
+        store: &R,
+    ) -> anyhow::Result<()> {
+        self.check_paths()?;
+        let staging = staging_path(destination.as_ref())?;
+        let result = async {
+            clear_stale(&staging)?;
+            afs::create_dir_all(&staging).await?;
+            for dirname in self.leaf_directories() {
+                afs::create_dir_all(staging.join(dirname)).await?;
+            }
+
+            let staging = &staging;
+            stream::iter(self.paths.iter())
+                .map(|(path, mode, id)| async move {
+                    let bytes = Self::read_blob(path, store.get(id).await?)?;
+                    install(staging.join(path), path.clone(), *mode, bytes).await
+                })
+                .buffer_unordered(UNPACK_CONCURRENCY)
+                .try_collect::<Vec<_>>()
+                .await?;
+            swap_into_place(staging, destination.as_ref())
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = afs::remove_dir_all(&staging).await;
+        }
+        result
+    }
+}
+
+const UNPACK_CONCURRENCY: usize = 64;
+
+fn staging_path(destination: &Path) -> anyhow::Result<PathBuf> {
+    let filename = match destination.file_name() {
+        Some(filename) => filename.to_string_lossy(),
+        None => bail!("cannot unpack into {:?}", destination),
+    };
+    Ok(destination.with_file_name(format!(".{}.eos-staging-{}", filename, std::process::id())))
+}
+
+// Removes a directory a crashed unpack may have left behind, if there is one.
+// Staging directories are named by pid, so a recycled pid would otherwise
+// build on top of another run's leftovers.
+fn clear_stale(path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_dir_all(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn retired_path(staging: &Path) -> PathBuf {
+    let mut retired = staging.as_os_str().to_owned();
+    retired.push("-old");
+    PathBuf::from(retired)
+}
+
+// Renames `staging` to `destination`. An existing destination is moved aside
+// to `<staging>-old` first and removed afterwards, so `destination` never
+// holds a mix of the two trees. It does not exist at all between the two
+// renames; a crash there leaves the old tree at `<staging>-old`, which the
+// next unpack with the same staging path clears.
+fn swap_into_place(staging: &Path, destination: &Path) -> anyhow::Result<()> {
+    if std::fs::symlink_metadata(destination).is_err() {
+        std::fs::rename(staging, destination)?;
+        return Ok(());
+    }
+
+    let retired = retired_path(staging);
+    clear_stale(&retired)?;
+    std::fs::rename(destination, &retired)?;
+    if let Err(e) = std::fs::rename(staging, destination) {
+        std::fs::rename(&retired, destination)?;
+        bail!(e);
+    }
+    std::fs::remove_dir_all(&retired)?;
+    Ok(())
+}
+
+pub struct VersionBuilder {
+    name: String,
+    version: String,
+    created: Option<DateTime<Utc>>,
+    dependencies: BTreeMap<(String, DependencyKind), String>,
+    paths: BTreeMap<String, (Mode, [u8; 32])>,
+}
+
+impl VersionBuilder {
+    pub fn new<N: AsRef<str>, V: AsRef<str>>(name: N, version: V) -> Self {
+        VersionBuilder {
+            name: name.as_ref().to_string(),
+            version: version.as_ref().to_string(),
+            created: None,
+            dependencies: BTreeMap::new(),
+            paths: BTreeMap::new(),
+        }
+    }
+
+    // Starts a builder from the name, version and each kind of dependency
+    // declared in a package.json document.
+    pub fn from_package_json<T: AsRef<[u8]>>(input: T) -> anyhow::Result<Self> {
+        let manifest: serde_json::Value = serde_json::from_slice(input.as_ref())?;
+        let mut builder = match (manifest["name"].as_str(), manifest["version"].as_str()) {
+            (Some(name), Some(version)) => VersionBuilder::new(name, version),
+            _ => bail!("package.json must have a string name and version"),
+        };
+
+        for kind in DEPENDENCY_KINDS.iter() {
+            if let Some(dependencies) = manifest[kind.field()].as_object() {
+                for (dependency, range) in dependencies {
+                    if let Some(range) = range.as_str() {
+                        builder = builder.typed_dependency(dependency, *kind, range);
+                    }
+                }
+            }
+        }
+        Ok(builder)
+    }
+
+    pub fn created<T: Into<DateTime<Utc>>>(mut self, created: T) -> Self {
+        self.created = Some(created.into());
+        self
+    }
+
+    pub fn dependency<N: AsRef<str>, R: AsRef<str>>(self, name: N, range: R) -> Self {
+        self.typed_dependency(name, DependencyKind::Regular, range)
+    }
+
+    pub fn typed_dependency<N: AsRef<str>, R: AsRef<str>>(
+        mut self,
+        name: N,
+        kind: DependencyKind,
+        range: R,
+    ) -> Self {
+        self.dependencies
+            .insert((name.as_ref().to_string(), kind), range.as_ref().to_string());
+        self
+    }
+
+    pub fn path<P: AsRef<str>>(self, path: P, id: &[u8; 32]) -> Self {
+        self.entry(path, Mode::Regular, id)
+    }
+
+    // Paths are stored NFC-normalised; see `Version::check_paths`.
+    pub fn entry<P: AsRef<str>>(mut self, path: P, mode: Mode, id: &[u8; 32]) -> Self {
+        self.paths.insert(path.as_ref().nfc().collect(), (mode, *id));
+        self
+    }
+
+    pub fn build(self) -> Version {
+        // The encoding only keeps whole seconds, so drop the rest up front
+        // to keep a built version equal to its decoded self.
+        let created = self.created.unwrap_or_else(Utc::now);
+        Version {
+            name: self.name,
+            version: self.version,
+            created: Utc.timestamp_opt(created.timestamp(), 0).unwrap(),
+            dependencies: self
+                .dependencies
+                .into_iter()
+                .map(|((name, kind), range)| (name, kind, range))
+                .collect(),
+            paths: self
+                .paths
+                .into_iter()
+                .map(|(path, (mode, id))| (path, mode, id))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stores::loose::LooseStore;
+    use crate::stores::WritableStore;
+    use crate::testing::{scratch_dir, scratch_store};
+    use sha2::Sha256;
+
+    fn fixture() -> Version {
+        VersionBuilder::new("left-pad", "1.0.0")
+            .created(Utc.timestamp_opt(1_500_000_000, 0).unwrap())
+            .dependency("right-pad", "^2.0.0")
+            .dependency("center-pad", "~1.1.0")
+            .typed_dependency("center-pad", DependencyKind::Dev, "^1.1.2")
+            .typed_dependency("tap", DependencyKind::Dev, "^14.0.0")
+            .path("package.json", &[1u8; 32])
+            .path("index.js", &[2u8; 32])
+            .build()
+    }
+
+    #[test]
+    fn version_roundtrip_works() {
+        let version = fixture();
+        let envelope = version.to_envelope().expect("failed to encode");
+        let decoded = Version::from_envelope(&envelope).expect("failed to decode");
+        assert_eq!(version, decoded);
+        assert_eq!(decoded.name(), "left-pad");
+        assert_eq!(decoded.dependencies()[0].0, "center-pad");
+        assert_eq!(decoded.dependencies()[1].1, DependencyKind::Dev);
+        assert_eq!(decoded.dependencies()[3].2, "^14.0.0");
+        assert_eq!(decoded.paths()[0].0, "index.js");
+    }
+
+    #[test]
+    fn version_rejects_noncanonical_bytes() {
+        let mut bytes = Vec::new();
+        fixture().to_bytes(&mut bytes).expect("failed to encode");
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Version::from_bytes(&trailing[..]).is_err());
+
+        // Rename the first path so the file map is no longer sorted.
+        let index = bytes.windows(8).position(|w| w == b"index.js").unwrap();
+        bytes[index..index + 8].copy_from_slice(b"zzzzz.js");
+        assert!(Version::from_bytes(&bytes[..]).is_err());
+    }
+
+    async fn unpack_fixture(name: &str) -> (LooseStore<Sha256>, Version) {
+        let store = scratch_store(name);
+        let mut builder = VersionBuilder::new("unpacked", "1.0.0");
+        for (path, data) in &[
+            ("package.json", &b"{}"[..]),
+            ("lib/index.js", &b"index"[..]),
+            ("lib/util/a.js", &b"a"[..]),
+            ("bin/cli.js", &b"cli"[..]),
+        ] {
+            let blob = Envelope::Blob(data.to_vec());
+            let (id, _) = blob.content_address::<Sha256>();
+            store.add(blob).await.expect("failed to add blob");
+            builder = builder.path(path, &id);
+        }
+        (store, builder.build())
+    }
+
+    // Encodes a version with the given paths and returns why decoding it
+    // failed.
+    fn rejection(paths: &[&str]) -> Option<PathError> {
+        let version = Version {
+            name: "unsafe".to_string(),
+            version: "1.0.0".to_string(),
+            created: Utc.timestamp_opt(0, 0).unwrap(),
+            dependencies: Vec::new(),
+            paths: paths.iter().map(|p| (p.to_string(), Mode::Regular, [0u8; 32])).collect(),
+        };
+        let envelope = version.to_envelope().unwrap();
+        let err = Version::from_envelope(&envelope).unwrap_err();
+        assert!(version.unpack_sync("unreachable", &()).is_err());
+        err.downcast::<PathError>().ok()
+    }
+
+    #[test]
+    fn version_rejects_unsafe_paths() {
+        let s = String::from;
+        assert_eq!(rejection(&["/etc/passwd"]), Some(PathError::Absolute(s("/etc/passwd"))));
+        assert_eq!(rejection(&["../x"]), Some(PathError::Traversal(s("../x"))));
+        assert_eq!(rejection(&["a/./b"]), Some(PathError::Traversal(s("a/./b"))));
+        assert_eq!(rejection(&["a//b"]), Some(PathError::Empty(s("a//b"))));
+        assert_eq!(rejection(&["a\\..\\b"]), Some(PathError::InvalidCharacter(s("a\\..\\b"))));
+        assert_eq!(
+            rejection(&["cafe\u{301}.js"]),
+            Some(PathError::NotNormalized(s("cafe\u{301}.js")))
+        );
+        assert_eq!(
+            rejection(&["README.md", "readme.md"]),
+            Some(PathError::CaseCollision(s("README.md"), s("readme.md")))
+        );
+        assert_eq!(
+            rejection(&["LIB", "lib/index.js"]),
+            Some(PathError::CaseCollision(s("LIB"), s("lib/index.js")))
+        );
+        assert_eq!(
+            rejection(&["lib", "lib/index.js"]),
+            Some(PathError::BeneathFile(s("lib"), s("lib/index.js")))
+        );
+    }
+
+    #[test]
+    fn links_stay_inside_the_package() {
+        assert!(check_link("bin/cli", "../lib/cli.js").is_ok());
+        assert!(check_link("bin/cli", "./cli.js").is_ok());
+        assert!(check_link("bin/cli", "..").is_ok());
+        assert!(check_link("a/b/c", "../../d/e").is_ok());
+
+        let escapes = |link: &str, target: &str| {
+            check_link(link, target)
+                == Err(PathError::LinkEscapes(link.to_string(), target.to_string()))
+        };
+        assert!(escapes("cli", "/usr/bin/node"));
+        assert!(escapes("cli", ".."));
+        assert!(escapes("bin/cli", "../../etc/passwd"));
+        assert!(escapes("bin/cli", "lib/../../.."));
+        assert!(escapes("bin/cli", "self/.."));
+        assert!(escapes("bin/cli", ""));
+        assert!(escapes("bin/cli", "..\\..\\x"));
+    }
+
+    #[test]
+    fn builder_normalises_paths() {
+        let version = VersionBuilder::new("nfc", "1.0.0")
+            .path("cafe\u{301}.js", &[0u8; 32])
+            .build();
+        assert_eq!(version.paths()[0].0, "caf\u{e9}.js");
+        assert!(version.check_paths().is_ok());
+    }
+
+    #[test]
+    fn leaf_directories_skip_ancestors() {
+        let version = VersionBuilder::new("dirs", "1.0.0")
+            .path("a/b/c.js", &[0u8; 32])
+            .path("a/d.js", &[0u8; 32])
+            .path("a-b/e.js", &[0u8; 32])
+            .path("f.js", &[0u8; 32])
+            .build();
+        assert_eq!(version.leaf_directories(), vec!["a-b", "a/b"]);
+    }
+
+    #[async_std::test]
+    async fn unpack_sync_writes_files() {
+        let (store, version) = unpack_fixture("unpack-sync").await;
+        let mut destination = scratch_dir("unpack-sync-dest");
+        destination.push("node_modules");
+        std::fs::create_dir_all(&destination).unwrap();
+        std::fs::write(destination.join("stale.js"), b"stale").unwrap();
+
+        version.unpack_sync(&destination, &store).expect("failed to unpack");
+        assert_eq!(std::fs::read(destination.join("lib/util/a.js")).unwrap(), b"a");
+        assert_eq!(std::fs::read(destination.join("bin/cli.js")).unwrap(), b"cli");
+        assert!(!destination.join("stale.js").exists());
+
+        // Leftovers of a crashed run with the same pid are not built upon.
+        let staging = staging_path(&destination).unwrap();
+        std::fs::create_dir_all(staging.join("leftover")).unwrap();
+        std::fs::create_dir_all(retired_path(&staging).join("old")).unwrap();
+        version.unpack_sync(&destination, &store).expect("failed to unpack");
+        assert!(!destination.join("leftover").exists());
+        assert!(!staging.exists());
+        assert!(!retired_path(&staging).exists());
+    }
+
+    #[async_std::test]
+    async fn unpack_writes_files_concurrently() {
+        let (store, version) = unpack_fixture("unpack-async").await;
+        let mut destination = scratch_dir("unpack-async-dest");
+        destination.push("pkg");
+
+        version.unpack(&destination, &store).await.expect("failed to unpack");
+        assert_eq!(std::fs::read(destination.join("lib/index.js")).unwrap(), b"index");
+        assert_eq!(std::fs::read(destination.join("package.json")).unwrap(), b"{}");
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn unpack_restores_modes_and_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let store = scratch_store("unpack-modes");
+        let mut ids = Vec::new();
+        for data in &[&b"#!/bin/sh"[..], &b"bin/cli"[..]] {
+            let blob = Envelope::Blob(data.to_vec());
+            ids.push(blob.content_address::<Sha256>().0);
+            store.add(blob).await.expect("failed to add blob");
+        }
+        let version = VersionBuilder::new("modes", "1.0.0")
+            .entry("bin/cli", Mode::Executable, &ids[0])
+            .entry("cli", Mode::Symlink, &ids[1])
+            .build();
+        let decoded = Version::from_envelope(&version.to_envelope().unwrap()).unwrap();
+        assert_eq!(version, decoded);
+
+        for (name, sync) in &[("unpack-modes-sync", true), ("unpack-modes-async", false)] {
+            let mut destination = scratch_dir(name);
+            destination.push("pkg");
+            if *sync {
+                decoded.unpack_sync(&destination, &store).expect("failed to unpack");
+            } else {
+                decoded.unpack(&destination, &store).await.expect("failed to unpack");
+            }
+
+            let metadata = std::fs::metadata(destination.join("bin/cli")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+            let target = std::fs::read_link(destination.join("cli")).unwrap();
+            assert_eq!(target, PathBuf::from("bin/cli"));
+            assert_eq!(std::fs::read(destination.join("cli")).unwrap(), b"#!/bin/sh");
+        }
+
+        // A target outside the package is refused whichever way it arrives.
+        let blob = Envelope::Blob(b"../../../etc".to_vec());
+        let (escaping, _) = blob.content_address::<Sha256>();
+        store.add(blob).await.expect("failed to add blob");
+        let version = VersionBuilder::new("escape", "1.0.0")
+            .entry("lib/etc", Mode::Symlink, &escaping)
+            .build();
+        let mut destination = scratch_dir("unpack-escape");
+        destination.push("pkg");
+        assert!(version.unpack_sync(&destination, &store).is_err());
+        assert!(version.unpack(&destination, &store).await.is_err());
+        assert!(!destination.exists());
+    }
+
+    #[async_std::test]
+    async fn unpack_sync_linked_shares_extracted_blobs() {
+        let (store, version) = unpack_fixture("unpack-linked").await;
+        let blobs = ExtractedBlobs::<Sha256>::new(scratch_dir("unpack-linked-cache"));
+        let projects = scratch_dir("unpack-linked-dest");
+        for project in &["one", "two"] {
+            let destination = projects.join(project);
+            version
+                .unpack_sync_linked(&destination, &store, &blobs)
+                .expect("failed to unpack");
+            assert_eq!(std::fs::read(destination.join("lib/util/a.js")).unwrap(), b"a");
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let links = std::fs::metadata(projects.join("one/bin/cli.js")).unwrap().nlink();
+            assert!(links >= 2);
+        }
+    }
+
+    #[test]
+    fn unpack_leaves_nothing_behind_on_failure() {
+        let version = VersionBuilder::new("missing", "1.0.0")
+            .path("lib/index.js", &[9u8; 32])
+            .build();
+        let mut destination = scratch_dir("unpack-missing");
+        destination.push("pkg");
 
-    pub fn unpack_sync<P: AsRef<Path>, R: ReadableStore>(&self, destination: P, store: &R) -> Result<()> {
-        // best version: paths -> unique dirnames -> reduced to those that don't start with the others
+        assert!(version.unpack_sync(&destination, &()).is_err());
+        assert!(!destination.exists());
+        assert_eq!(std::fs::read_dir(destination.parent().unwrap()).unwrap().count(), 0);
     }
 }
@@ -0,0 +1,848 @@
+use crate::objects::event::{Claim, Event, EventIterator, EventStream};
+use crate::stores::ReadableStore;
+use anyhow::{self, bail};
+use futures::stream::StreamExt;
+use sodiumoxide::crypto::sign::ed25519::PublicKey;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use thiserror::Error;
+
+// Event walks skip parents missing from the store; a history has to be whole.
+fn check_complete(events: &[([u8; 32], Event)]) -> anyhow::Result<()> {
+    let ids: HashSet<_> = events.iter().map(|(id, _)| *id).collect();
+    for (id, event) in events.iter() {
+        for parent in event.parents() {
+            if !ids.contains(parent) {
+                bail!(
+                    "could not find event {}, a parent of {}",
+                    hex::encode(parent),
+                    hex::encode(id)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Loads every event reachable from `heads` and returns them parents-first,
+// as `Traversal::Topological` walks them. Clocks only break ties between
+// events that are ready together, and the order depends only on the DAG and
+// the set of heads.
+pub async fn history<S: ReadableStore + Sync>(
+    store: &S,
+    heads: &[[u8; 32]],
+) -> anyhow::Result<Vec<([u8; 32], Event)>> {
+    let mut stream = EventStream::new(store, heads).topological();
+    let mut events = Vec::new();
+    while let Some(item) = stream.next().await {
+        events.push(item?);
+    }
+    check_complete(&events)?;
+    Ok(events)
+}
+
+// As `history`, for callers that cannot await.
+pub fn history_sync<S: ReadableStore>(
+    store: &S,
+    heads: &[[u8; 32]],
+) -> anyhow::Result<Vec<([u8; 32], Event)>> {
+    let events = EventIterator::new(store, heads)?
+        .topological()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    check_complete(&events)?;
+    Ok(events)
+}
+
+// The piece of package state a claim writes. Each subject holds one value:
+// an authority's key, a version's publication, whether a version is yanked,
+// or a tag's version.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Subject {
+    Authority(String),
+    Publication(String),
+    Yank(String),
+    Tag(String),
+}
+
+impl Subject {
+    fn of(claim: &Claim) -> Option<Self> {
+        match claim {
+            Claim::AuthorityAdd { name, .. } | Claim::AuthorityRemove { name } => {
+                Some(Subject::Authority(name.clone()))
+            }
+            Claim::Publication { version, .. } => Some(Subject::Publication(version.clone())),
+            Claim::Yank { version, .. } | Claim::Unyank { version } => {
+                Some(Subject::Yank(version.clone()))
+            }
+            Claim::Tag { tag, .. } => Some(Subject::Tag(tag.clone())),
+            Claim::Other { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Authority(name) => write!(f, "authority {}", name),
+            Subject::Publication(version) => write!(f, "publication {}", version),
+            Subject::Yank(version) => write!(f, "yank {}", version),
+            Subject::Tag(tag) => write!(f, "tag {}", tag),
+        }
+    }
+}
+
+// Two events that wrote the same subject without either descending from the
+// other. The write from `kept` stands; the one from `dropped` is ignored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub subject: Subject,
+    pub kept: [u8; 32],
+    pub dropped: [u8; 32],
+}
+
+// Whether the event at `ancestor` is reachable from the parents of the event
+// at `descendant`. Both index `events`, which is in causal order, so nothing
+// earlier than `ancestor` can lead to it.
+fn descends(
+    events: &[(&[u8; 32], &Event)],
+    positions: &HashMap<[u8; 32], usize>,
+    ancestor: usize,
+    descendant: usize,
+) -> bool {
+    let mut seen = HashSet::new();
+    let mut pending = vec![descendant];
+    while let Some(idx) = pending.pop() {
+        for parent in events[idx].1.parents() {
+            match positions.get(parent) {
+                Some(&pos) if pos == ancestor => return true,
+                Some(&pos) if pos > ancestor && seen.insert(pos) => pending.push(pos),
+                _ => {}
+            }
+        }
+    }
+    false
+}
+
+// Applies `events`, which must be in causal order, to an empty state. A claim
+// replaces the value of its subject if the event that wrote that value is one
+// of its ancestors. Concurrent writes are settled by last-writer-wins on
+// (at, id): the event with the later timestamp wins, and the greater id wins
+// a tie. Every such settlement is returned as a conflict.
+//
+// `at` is chosen by the signer, so any authority can date an event in the
+// future to win every race it enters. For tags, yanks and publications that
+// buys nothing an authority could not do with a plain later event. Removing
+// an authority is different: the removed key cannot sign anything after it,
+// so it could only stay by racing its removal with a future-dated re-add. A
+// removal therefore beats a concurrent write to the same authority whatever
+// the clocks say.
+fn fold(events: &[(&[u8; 32], &Event)]) -> anyhow::Result<(PackageState, Vec<Conflict>)> {
+    let positions: HashMap<_, _> = events
+        .iter()
+        .enumerate()
+        .map(|(idx, (id, _))| (**id, idx))
+        .collect();
+    let mut state = PackageState::default();
+    // The event that last wrote each subject, and whether it was a removal.
+    let mut writers: HashMap<Subject, (usize, bool)> = HashMap::new();
+    let mut conflicts = Vec::new();
+
+    for (idx, (id, event)) in events.iter().enumerate() {
+        for claim in event.claims() {
+            let subject = match Subject::of(claim) {
+                Some(subject) => subject,
+                None => continue,
+            };
+            let removes = matches!(claim, Claim::AuthorityRemove { .. });
+            if let Some(&(writer, writer_removes)) = writers.get(&subject) {
+                if writer != idx && !descends(events, &positions, writer, idx) {
+                    let (writer_id, writer_event) = events[writer];
+                    let keep_existing = (writer_removes, writer_event.at(), writer_id)
+                        > (removes, event.at(), *id);
+                    let (kept, dropped) = if keep_existing {
+                        (*writer_id, **id)
+                    } else {
+                        (**id, *writer_id)
+                    };
+                    conflicts.push(Conflict {
+                        subject: subject.clone(),
+                        kept,
+                        dropped,
+                    });
+                    if keep_existing {
+                        continue;
+                    }
+                }
+            }
+            state.apply_claim(claim)?;
+            writers.insert(subject, (idx, removes));
+        }
+    }
+    Ok((state, conflicts))
+}
+
+// Folds a causally ordered history, such as `history` returns, into a state,
+// along with the conflicts between concurrent writes settled on the way.
+pub fn replay(events: &[([u8; 32], Event)]) -> anyhow::Result<(PackageState, Vec<Conflict>)> {
+    let events: Vec<_> = events.iter().map(|(id, event)| (id, event)).collect();
+    fold(&events[..])
+}
+
+// Why an event's signature is not backed by the package's history.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("Genesis event does not make its signatory \"{0}\" an authority")]
+    NotSelfAuthorising(String),
+    #[error("Signatory \"{0}\" is not an authority")]
+    NotAuthority(String),
+    #[error("Authority \"{0}\" has a malformed public key")]
+    MalformedKey(String),
+    #[error("Signature does not match the key of \"{0}\"")]
+    BadSignature(String),
+}
+
+// Checks the signature of `event` against `authorities`, returning the
+// hex-encoded key it was made with.
+fn check_signature(authorities: &PackageState, event: &Event) -> Result<String, SignatureError> {
+    let signatory = event.signatory();
+    let key = match authorities.authority(signatory) {
+        Some(key) => key,
+        None if event.parents().is_empty() => {
+            return Err(SignatureError::NotSelfAuthorising(signatory.to_string()))
+        }
+        None => return Err(SignatureError::NotAuthority(signatory.to_string())),
+    };
+    let public_key = hex::decode(key)
+        .ok()
+        .and_then(|bytes| PublicKey::from_slice(&bytes[..]))
+        .ok_or_else(|| SignatureError::MalformedKey(signatory.to_string()))?;
+    match event.verify(&public_key) {
+        Ok(true) => Ok(key.to_string()),
+        _ => Err(SignatureError::BadSignature(signatory.to_string())),
+    }
+}
+
+// Walks the history behind `heads` from its genesis and checks that every
+// event is signed by an authority of its parents' state; a genesis event must
+// instead be signed by an authority it adds itself. Returns every event that
+// fails, in causal order. The claims of a failing event are ignored when
+// checking its descendants, so a forged event cannot vouch for others.
+pub fn verify_history<S: ReadableStore>(
+    store: &S,
+    heads: &[[u8; 32]],
+) -> anyhow::Result<Vec<([u8; 32], SignatureError)>> {
+    Ok(verify_events(&history_sync(store, heads)?)?.failures)
+}
+
+// The outcome of `verify_events`: the events that failed, and the
+// hex-encoded key that signed each of the rest.
+struct Verification {
+    failures: Vec<([u8; 32], SignatureError)>,
+    keys: HashMap<[u8; 32], String>,
+}
+
+// As `verify_history`, over a causally ordered history.
+fn verify_events(events: &[([u8; 32], Event)]) -> anyhow::Result<Verification> {
+    let by_id: HashMap<_, _> = events.iter().map(|(id, event)| (*id, event)).collect();
+    let mut states: HashMap<[u8; 32], PackageState> = HashMap::new();
+    let mut rejected = HashSet::new();
+    let mut failures = Vec::new();
+    let mut keys = HashMap::new();
+
+    for (id, event) in events.iter() {
+        let before = match event.parents() {
+            [] => PackageState::default(),
+            [parent] => states[parent].clone(),
+            parents => {
+                // Replay the union of the parents' histories, skipping the
+                // events whose claims were rejected.
+                let mut ancestors = HashSet::new();
+                let mut pending = parents.to_vec();
+                while let Some(ancestor) = pending.pop() {
+                    if ancestors.insert(ancestor) {
+                        pending.extend(by_id[&ancestor].parents().iter().cloned());
+                    }
+                }
+                let accepted: Vec<_> = events
+                    .iter()
+                    .filter(|(id, _)| ancestors.contains(id) && !rejected.contains(id))
+                    .map(|(id, event)| (id, event))
+                    .collect();
+                fold(&accepted[..])?.0
+            }
+        };
+
+        let mut after = before.clone();
+        after.apply(event)?;
+        let authorities = if event.parents().is_empty() { &after } else { &before };
+        match check_signature(authorities, event) {
+            Ok(key) => {
+                keys.insert(*id, key);
+                states.insert(*id, after)
+            }
+            Err(err) => {
+                rejected.insert(*id);
+                failures.push((*id, err));
+                states.insert(*id, before)
+            }
+        };
+    }
+    Ok(Verification { failures, keys })
+}
+
+// Evidence that an authority signed two incompatible sibling events: neither
+// descends from the other, yet both write each of `subjects` with a different
+// value, so each branch could be shown to different users as the package's
+// history. `key` is the hex-encoded public key that made both signatures,
+// which lets anyone check the evidence without the rest of the graph.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Equivocation {
+    pub signatory: String,
+    pub key: String,
+    pub subjects: Vec<Subject>,
+    pub first: [u8; 32],
+    pub first_signature: Vec<u8>,
+    pub second: [u8; 32],
+    pub second_signature: Vec<u8>,
+}
+
+// Whether two writes to the same subject leave it in the same state. Yanks
+// that only give different reasons agree that the version is yanked.
+fn agree(first: &Claim, second: &Claim) -> bool {
+    match (first, second) {
+        (Claim::Yank { .. }, Claim::Yank { .. }) => true,
+        _ => first == second,
+    }
+}
+
+// Everything wrong with the history behind `heads`: the events whose
+// signatures it does not back, and the equivocations among the rest.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Audit {
+    pub rejected: Vec<([u8; 32], SignatureError)>,
+    pub equivocations: Vec<Equivocation>,
+}
+
+// Verifies the history behind `heads` and scans it for equivocations, loading
+// it once for both. Equivocations are ordered by where their events fall in
+// causal order. Events that fail verification are left out of them: a forgery
+// says nothing about the authority it names. Writes are grouped by the key
+// that signed them rather than the signatory's name, so an authority that
+// rotated its key is not taken to contradict itself.
+pub fn audit<S: ReadableStore>(store: &S, heads: &[[u8; 32]]) -> anyhow::Result<Audit> {
+    let history = history_sync(store, heads)?;
+    let Verification {
+        failures: rejected,
+        keys,
+    } = verify_events(&history)?;
+    let events: Vec<_> = history.iter().map(|(id, event)| (id, event)).collect();
+    let positions: HashMap<_, _> = events
+        .iter()
+        .enumerate()
+        .map(|(idx, (id, _))| (**id, idx))
+        .collect();
+
+    let mut writes: HashMap<(&str, Subject), Vec<(usize, &Claim)>> = HashMap::new();
+    for (idx, (id, event)) in events.iter().enumerate() {
+        let key = match keys.get(*id) {
+            Some(key) => key,
+            None => continue,
+        };
+        for claim in event.claims() {
+            if let Some(subject) = Subject::of(claim) {
+                writes.entry((key, subject)).or_default().push((idx, claim));
+            }
+        }
+    }
+
+    let mut found: BTreeMap<(usize, usize), BTreeSet<Subject>> = BTreeMap::new();
+    for ((_, subject), claims) in writes {
+        for (n, &(first, first_claim)) in claims.iter().enumerate() {
+            for &(second, second_claim) in claims[n + 1..].iter() {
+                if first != second
+                    && !agree(first_claim, second_claim)
+                    && !descends(&events, &positions, first, second)
+                {
+                    found.entry((first, second)).or_default().insert(subject.clone());
+                }
+            }
+        }
+    }
+
+    let equivocations = found
+        .into_iter()
+        .map(|((first, second), subjects)| {
+            let (first_id, first_event) = events[first];
+            let (second_id, second_event) = events[second];
+            Equivocation {
+                signatory: first_event.signatory().to_string(),
+                key: keys[first_id].clone(),
+                subjects: subjects.into_iter().collect(),
+                first: *first_id,
+                first_signature: first_event.signature().to_vec(),
+                second: *second_id,
+                second_signature: second_event.signature().to_vec(),
+            }
+        })
+        .collect();
+    Ok(Audit {
+        rejected,
+        equivocations,
+    })
+}
+
+// The equivocations half of `audit`.
+pub fn find_equivocations<S: ReadableStore>(
+    store: &S,
+    heads: &[[u8; 32]],
+) -> anyhow::Result<Vec<Equivocation>> {
+    Ok(audit(store, heads)?.equivocations)
+}
+
+// What a package's events say about it: who may sign for it, the id each
+// version was published under, which versions are yanked and why, and where
+// each tag points. Authorities map a name to a hex-encoded ed25519 public key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PackageState {
+    authorities: BTreeMap<String, String>,
+    versions: BTreeMap<String, [u8; 32]>,
+    yanked: BTreeMap<String, String>,
+    tags: BTreeMap<String, String>,
+}
+
+impl PackageState {
+    // Folds the verified history behind `head` into a state.
+    pub async fn load<S: ReadableStore + Sync>(store: &S, head: &[u8; 32]) -> anyhow::Result<Self> {
+        Self::verified(&history(store, &[*head]).await?)
+    }
+
+    // Replays the history behind `heads` from its roots, applying each claim
+    // in causal order. An empty `heads` gives the state before a package's
+    // first event.
+    pub fn load_sync<S: ReadableStore>(store: &S, heads: &[[u8; 32]]) -> anyhow::Result<Self> {
+        Self::verified(&history_sync(store, heads)?)
+    }
+
+    // Folds a causally ordered history, leaving out the events that
+    // `verify_events` rejects so a forged claim cannot grant authority or
+    // move a tag.
+    fn verified(events: &[([u8; 32], Event)]) -> anyhow::Result<Self> {
+        let rejected: HashSet<_> = verify_events(events)?
+            .failures
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        let accepted: Vec<_> = events
+            .iter()
+            .filter(|(id, _)| !rejected.contains(id))
+            .map(|(id, event)| (id, event))
+            .collect();
+        Ok(fold(&accepted[..])?.0)
+    }
+
+    pub fn apply(&mut self, event: &Event) -> anyhow::Result<()> {
+        for claim in event.claims() {
+            self.apply_claim(claim)?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_claim(&mut self, claim: &Claim) -> anyhow::Result<()> {
+        match claim {
+            Claim::AuthorityAdd { public_key, name } => {
+                self.authorities.insert(name.clone(), public_key.clone());
+            }
+            Claim::AuthorityRemove { name } => {
+                self.authorities.remove(name);
+            }
+            Claim::Publication { version, id } => {
+                if id.len() != 32 {
+                    bail!("publication of {} has a malformed id", version);
+                }
+                let mut oid = [0u8; 32];
+                oid.copy_from_slice(&id[..]);
+                self.versions.insert(version.clone(), oid);
+            }
+            Claim::Yank { version, reason } => {
+                self.yanked.insert(version.clone(), reason.clone());
+            }
+            Claim::Unyank { version } => {
+                self.yanked.remove(version);
+            }
+            Claim::Tag { tag, version } => {
+                self.tags.insert(tag.clone(), version.clone());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Every published version, yanked or not, in no particular order.
+    pub fn versions(&self) -> impl Iterator<Item = (&str, &[u8; 32])> {
+        self.versions.iter().map(|(version, id)| (version.as_str(), id))
+    }
+
+    pub fn version<T: AsRef<str>>(&self, version: T) -> Option<&[u8; 32]> {
+        self.versions.get(version.as_ref())
+    }
+
+    pub fn is_yanked<T: AsRef<str>>(&self, version: T) -> bool {
+        self.yanked.contains_key(version.as_ref())
+    }
+
+    pub fn yank_reason<T: AsRef<str>>(&self, version: T) -> Option<&str> {
+        self.yanked.get(version.as_ref()).map(|reason| reason.as_str())
+    }
+
+    pub fn authorities(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.authorities
+            .iter()
+            .map(|(name, key)| (name.as_str(), key.as_str()))
+    }
+
+    // The hex-encoded public key registered under `name`.
+    pub fn authority<T: AsRef<str>>(&self, name: T) -> Option<&str> {
+        self.authorities.get(name.as_ref()).map(|key| key.as_str())
+    }
+
+    // Whether `name` is an authority registered with `public_key`.
+    pub fn is_authority<T: AsRef<str>>(&self, name: T, public_key: &PublicKey) -> bool {
+        self.authority(name) == Some(hex::encode(public_key).as_str())
+    }
+
+    pub fn tag<T: AsRef<str>>(&self, tag: T) -> Option<&str> {
+        self.tags.get(tag.as_ref()).map(|version| version.as_str())
+    }
+
+    pub fn tags(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.tags
+            .iter()
+            .map(|(tag, version)| (tag.as_str(), version.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+    use crate::objects::event::EventBuilder;
+    use crate::testing::{
+        add_event, add_event_as, add_event_bytes, authority, publication, scratch_store,
+    };
+    use sodiumoxide::crypto::sign;
+
+    #[async_std::test]
+    async fn state_replays_claims_in_causal_order() {
+        let store = scratch_store("package-state");
+        let (pk, sk) = sign::gen_keypair();
+        let (helper, _) = sign::gen_keypair();
+
+        let root = EventBuilder::new()
+            .claim(Claim::AuthorityAdd {
+                public_key: hex::encode(pk),
+                name: "test".to_string(),
+            })
+            .claim(publication("1.0.0", [1; 32]))
+            .claim(Claim::Yank {
+                version: "1.0.0".to_string(),
+                reason: "broken".to_string(),
+            });
+        let root = add_event(&store, &sk, 1000, root).await;
+
+        // Dated before its parent: replay must follow parents, not clocks.
+        let next = EventBuilder::new()
+            .parent(root)
+            .claim(publication("1.1.0", [2; 32]))
+            .claim(Claim::Unyank {
+                version: "1.0.0".to_string(),
+            })
+            .claim(Claim::AuthorityAdd {
+                public_key: hex::encode(helper),
+                name: "helper".to_string(),
+            });
+        let next = add_event(&store, &sk, 500, next).await;
+
+        let head = EventBuilder::new()
+            .parent(next)
+            .claim(Claim::Yank {
+                version: "1.1.0".to_string(),
+                reason: "superseded".to_string(),
+            })
+            .claim(Claim::AuthorityRemove {
+                name: "helper".to_string(),
+            });
+        let head = add_event(&store, &sk, 2000, head).await;
+
+        let state = PackageState::load_sync(&store, &[head]).expect("failed to load");
+        assert_eq!(
+            state.authorities().collect::<Vec<_>>(),
+            vec![("test", hex::encode(pk).as_str())]
+        );
+        assert_eq!(
+            state.versions().map(|(version, _)| version).collect::<Vec<_>>(),
+            vec!["1.0.0", "1.1.0"]
+        );
+        assert!(!state.is_yanked("1.0.0"));
+        assert_eq!(state.yank_reason("1.1.0"), Some("superseded"));
+        assert_eq!(PackageState::load(&store, &head).await.unwrap(), state);
+
+        let before = PackageState::load_sync(&store, &[next]).expect("failed to load");
+        assert_eq!(before.authority("helper"), Some(hex::encode(helper).as_str()));
+        assert_eq!(before.yank_reason("1.1.0"), None);
+        assert_eq!(PackageState::load_sync(&store, &[]).unwrap(), PackageState::default());
+    }
+
+    #[async_std::test]
+    async fn verify_history_reports_unauthorised_events() {
+        let store = scratch_store("package-verify");
+        let (pk, sk) = sign::gen_keypair();
+        let (rogue_pk, rogue_sk) = sign::gen_keypair();
+
+        let genesis = EventBuilder::new().claim(authority("test", &pk));
+        let genesis = add_event(&store, &sk, 1000, genesis).await;
+        assert_eq!(verify_history(&store, &[genesis]).unwrap(), vec![]);
+
+        // A correctly signed event whose signature is damaged afterwards.
+        let forged = EventBuilder::new()
+            .parent(genesis)
+            .claim(authority("rogue", &rogue_pk))
+            .sign("test", &sk, &store)
+            .expect("failed to sign");
+        let mut buf = Vec::new();
+        forged.to_bytes(&mut buf).expect("failed to encode");
+        *buf.last_mut().unwrap() ^= 0xff;
+        let forged = add_event_bytes(&store, buf).await;
+
+        // Signing ignores the forged authority, so the rogue has to bypass it.
+        assert!(EventBuilder::new()
+            .parent(forged)
+            .sign("rogue", &rogue_sk, &store)
+            .is_err());
+        let vouched = EventBuilder::new()
+            .parent(forged)
+            .sign_unchecked("rogue", &rogue_sk);
+        let mut buf = Vec::new();
+        vouched.to_bytes(&mut buf).expect("failed to encode");
+        let vouched = add_event_bytes(&store, buf).await;
+
+        let honest = add_event(&store, &sk, 2000, EventBuilder::new().parent(genesis)).await;
+        let merge = EventBuilder::new().parent(vouched).parent(honest);
+        let merge = add_event(&store, &sk, 3000, merge).await;
+
+        assert_eq!(
+            verify_history(&store, &[merge]).unwrap(),
+            vec![
+                (forged, SignatureError::BadSignature("test".to_string())),
+                (vouched, SignatureError::NotAuthority("rogue".to_string())),
+            ]
+        );
+        let state = PackageState::load_sync(&store, &[merge]).unwrap();
+        assert_eq!(state.authority("rogue"), None);
+
+        let orphan = EventBuilder::new().sign_unchecked("nobody", &sk);
+        let mut buf = Vec::new();
+        orphan.to_bytes(&mut buf).expect("failed to encode");
+        let orphan = add_event_bytes(&store, buf).await;
+        assert_eq!(
+            verify_history(&store, &[orphan]).unwrap(),
+            vec![(orphan, SignatureError::NotSelfAuthorising("nobody".to_string()))]
+        );
+    }
+
+    #[async_std::test]
+    async fn concurrent_writes_merge_by_last_writer() {
+        let store = scratch_store("package-merge");
+        let (pk, sk) = sign::gen_keypair();
+        let tag = |version: &str| Claim::Tag {
+            tag: "latest".to_string(),
+            version: version.to_string(),
+        };
+
+        let root = EventBuilder::new()
+            .claim(authority("test", &pk))
+            .claim(publication("1.0.0", [1; 32]))
+            .claim(publication("1.1.0", [2; 32]))
+            .claim(Claim::Yank {
+                version: "1.1.0".to_string(),
+                reason: "old".to_string(),
+            });
+        let root = add_event(&store, &sk, 1000, root).await;
+        let left = EventBuilder::new()
+            .parent(root)
+            .claim(Claim::Unyank {
+                version: "1.1.0".to_string(),
+            })
+            .claim(tag("1.1.0"));
+        let left = add_event(&store, &sk, 2000, left).await;
+        let right = EventBuilder::new()
+            .parent(root)
+            .claim(tag("1.0.0"))
+            .claim(Claim::Yank {
+                version: "1.1.0".to_string(),
+                reason: "worse".to_string(),
+            });
+        let right = add_event(&store, &sk, 3000, right).await;
+
+        let (merge, conflicts) = EventBuilder::merge(&store, &[left, right]).unwrap();
+        assert_eq!(
+            conflicts,
+            vec![
+                Conflict {
+                    subject: Subject::Tag("latest".to_string()),
+                    kept: right,
+                    dropped: left,
+                },
+                Conflict {
+                    subject: Subject::Yank("1.1.0".to_string()),
+                    kept: right,
+                    dropped: left,
+                },
+            ]
+        );
+        let merge = add_event(&store, &sk, 4000, merge).await;
+        let state = PackageState::load(&store, &merge).await.unwrap();
+        assert_eq!(state.tag("latest"), Some("1.0.0"));
+        assert_eq!(state.yank_reason("1.1.0"), Some("worse"));
+        assert_eq!(PackageState::load_sync(&store, &[right, left]).unwrap(), state);
+
+        // A descendant overrides whatever its clock says, and merges that
+        // only repeat settled conflicts report nothing.
+        let (_, conflicts) = EventBuilder::merge(&store, &[merge, left]).unwrap();
+        assert_eq!(conflicts, vec![]);
+        let late = EventBuilder::new().parent(merge).claim(tag("1.1.0"));
+        let late = add_event(&store, &sk, 500, late).await;
+        let state = PackageState::load_sync(&store, &[late]).unwrap();
+        assert_eq!(state.tag("latest"), Some("1.1.0"));
+    }
+
+    #[async_std::test]
+    async fn removals_beat_future_dated_authority_writes() {
+        let store = scratch_store("package-removal");
+        let (pk, sk) = sign::gen_keypair();
+        let (helper_pk, helper_sk) = sign::gen_keypair();
+
+        let root = EventBuilder::new()
+            .claim(authority("test", &pk))
+            .claim(authority("helper", &helper_pk));
+        let root = add_event(&store, &sk, 1000, root).await;
+        let removal = EventBuilder::new().parent(root).claim(Claim::AuthorityRemove {
+            name: "helper".to_string(),
+        });
+        let removal = add_event(&store, &sk, 2000, removal).await;
+        let readd = EventBuilder::new().parent(root).claim(authority("helper", &helper_pk));
+        let readd = add_event_as(&store, "helper", &helper_sk, 9_999_999_999, readd).await;
+
+        let (merge, conflicts) = EventBuilder::merge(&store, &[removal, readd]).unwrap();
+        assert_eq!(
+            conflicts,
+            vec![Conflict {
+                subject: Subject::Authority("helper".to_string()),
+                kept: removal,
+                dropped: readd,
+            }]
+        );
+        let merge = add_event(&store, &sk, 3000, merge).await;
+        let state = PackageState::load(&store, &merge).await.unwrap();
+        assert_eq!(state.authority("helper"), None);
+    }
+
+    #[async_std::test]
+    async fn equivocating_siblings_are_reported() {
+        let store = scratch_store("package-equivocation");
+        let (pk, sk) = sign::gen_keypair();
+        let (helper_pk, helper_sk) = sign::gen_keypair();
+        let tag = |tag: &str, version: &str| Claim::Tag {
+            tag: tag.to_string(),
+            version: version.to_string(),
+        };
+        let yank = |reason: &str| Claim::Yank {
+            version: "2.0.0".to_string(),
+            reason: reason.to_string(),
+        };
+
+        let root = EventBuilder::new()
+            .claim(authority("test", &pk))
+            .claim(authority("helper", &helper_pk))
+            .claim(publication("1.0.0", [1; 32]))
+            .claim(publication("2.0.0", [2; 32]));
+        let root = add_event(&store, &sk, 1000, root).await;
+        let left = EventBuilder::new()
+            .parent(root)
+            .claim(tag("latest", "1.0.0"))
+            .claim(yank("bad"));
+        let left = add_event(&store, &sk, 2000, left).await;
+        let right = EventBuilder::new()
+            .parent(root)
+            .claim(tag("latest", "2.0.0"))
+            .claim(yank("worse"));
+        let right = add_event(&store, &sk, 3000, right).await;
+
+        // Siblings that agree, and a descendant that changes its mind.
+        let agree = EventBuilder::new().parent(root).claim(tag("stable", "1.0.0"));
+        let agree = add_event(&store, &sk, 2500, agree).await;
+        let agree_again = EventBuilder::new().parent(root).claim(tag("stable", "1.0.0"));
+        let agree_again = add_event(&store, &sk, 2600, agree_again).await;
+        let child = EventBuilder::new().parent(left).claim(tag("latest", "2.0.0"));
+        let child = add_event(&store, &sk, 4000, child).await;
+
+        // A different authority disagreeing is a conflict, not equivocation.
+        let helper = EventBuilder::new()
+            .parent(root)
+            .claim(tag("latest", "2.0.0"))
+            .at(Utc.timestamp_opt(2100, 0).unwrap())
+            .sign("helper", &helper_sk, &store)
+            .expect("failed to sign");
+        let mut buf = Vec::new();
+        helper.to_bytes(&mut buf).expect("failed to encode");
+        let helper = add_event_bytes(&store, buf).await;
+
+        // A forgery in the authority's name is not evidence against it.
+        let forged = EventBuilder::new()
+            .parent(root)
+            .claim(tag("stable", "2.0.0"))
+            .sign("test", &sk, &store)
+            .expect("failed to sign");
+        let mut buf = Vec::new();
+        forged.to_bytes(&mut buf).expect("failed to encode");
+        *buf.last_mut().unwrap() ^= 0xff;
+        let forged = add_event_bytes(&store, buf).await;
+
+        // Nor is a write made with a key the authority has since replaced.
+        let (new_pk, new_sk) = sign::gen_keypair();
+        let rotate = EventBuilder::new()
+            .parent(root)
+            .claim(Claim::AuthorityRemove {
+                name: "test".to_string(),
+            })
+            .claim(authority("test", &new_pk));
+        let rotate = add_event(&store, &sk, 2200, rotate).await;
+        let rotated = EventBuilder::new().parent(rotate).claim(tag("stable", "2.0.0"));
+        let rotated = add_event(&store, &new_sk, 2300, rotated).await;
+
+        let heads = [child, right, agree, agree_again, helper, forged, rotated];
+        let history = history_sync(&store, &heads).unwrap();
+        let position = |id| history.iter().position(|(other, _)| *other == id);
+        let (first, second) = if position(left) < position(right) {
+            (left, right)
+        } else {
+            (right, left)
+        };
+        let events: HashMap<_, _> = history.into_iter().collect();
+        let found = audit(&store, &heads).unwrap();
+        assert_eq!(found.rejected.len(), 1);
+        assert_eq!(found.rejected[0].0, forged);
+        assert_eq!(
+            found.equivocations,
+            vec![Equivocation {
+                signatory: "test".to_string(),
+                key: hex::encode(pk),
+                subjects: vec![Subject::Tag("latest".to_string())],
+                first,
+                first_signature: events[&first].signature().to_vec(),
+                second,
+                second_signature: events[&second].signature().to_vec(),
+            }]
+        );
+        assert_eq!(find_equivocations(&store, &[child, agree]).unwrap(), vec![]);
+    }
+}
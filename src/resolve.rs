@@ -0,0 +1,416 @@
+use crate::objects::version::{DependencyKind, Version};
+use crate::package::PackageState;
+use crate::spec::{self, PackageArg, SpecError};
+use crate::stores::ReadableStore;
+use anyhow::{self, bail};
+use semver::VersionReq;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ResolveError {
+    #[error("{parent} depends on {name}, which is not a known package")]
+    UnknownPackage { name: String, parent: String },
+    #[error("no version of {name} satisfies {range:?}, required by {parent}")]
+    Unsatisfiable {
+        name: String,
+        range: String,
+        parent: String,
+    },
+    #[error("{0} is not a known package")]
+    NotFound(String),
+    #[error("nothing published matches {0}")]
+    NoMatch(String),
+    #[error("{parent} has an invalid dependency on {name}@{range}")]
+    InvalidDependency {
+        name: String,
+        range: String,
+        parent: String,
+        #[source]
+        source: SpecError,
+    },
+}
+
+// A resolved dependency graph. Every version reached has an entry mapping
+// each dependency name to the version id chosen for it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Graph {
+    pub root: [u8; 32],
+    pub edges: BTreeMap<[u8; 32], BTreeMap<String, [u8; 32]>>,
+}
+
+impl Graph {
+    pub fn versions(&self) -> impl Iterator<Item = &[u8; 32]> {
+        self.edges.keys()
+    }
+}
+
+const OPERATOR_CHARS: &str = "<>=~^";
+
+// Rewrites one npm comparator in the syntax the semver crate expects. npm
+// reads a bare version as exact where semver reads it as a caret range, and
+// spells wildcards "x" as well as "*".
+fn comparator(token: &str) -> String {
+    let split = token
+        .find(|c: char| !OPERATOR_CHARS.contains(c))
+        .unwrap_or(token.len());
+    let (op, version) = token.split_at(split);
+    let version = version.trim_start_matches('v');
+    let parts: Vec<&str> = version
+        .split('.')
+        .take_while(|part| !matches!(*part, "x" | "X" | "*"))
+        .collect();
+    if parts.is_empty() {
+        return "*".to_string();
+    }
+
+    let op = if op.is_empty() { "=" } else { op };
+    format!("{}{}", op, parts.join("."))
+}
+
+// Parses an npm range into alternatives, any of which may match. Returns
+// None for anything that is not a semver range, such as a tag name or a
+// git or file specifier.
+pub fn parse_range<T: AsRef<str>>(range: T) -> Option<Vec<VersionReq>> {
+    range
+        .as_ref()
+        .split("||")
+        .map(|alternative| {
+            let alternative = alternative.trim();
+            let comparators = match alternative.find(" - ") {
+                Some(idx) => vec![
+                    comparator(&format!(">={}", alternative[..idx].trim())),
+                    comparator(&format!("<={}", alternative[idx + 3..].trim())),
+                ],
+                None => {
+                    let mut comparators = Vec::new();
+                    let mut op = String::new();
+                    for token in alternative.split_whitespace() {
+                        if token.chars().all(|c| OPERATOR_CHARS.contains(c)) {
+                            op.push_str(token);
+                        } else {
+                            comparators.push(comparator(&format!("{}{}", op, token)));
+                            op.clear();
+                        }
+                    }
+                    if !op.is_empty() {
+                        return None;
+                    }
+                    comparators
+                }
+            };
+
+            if comparators.is_empty() || alternative == "latest" {
+                return Some(VersionReq::STAR);
+            }
+            VersionReq::parse(&comparators.join(", ")).ok()
+        })
+        .collect()
+}
+
+// Picks the version `range` names in `state`: the version a tag points at,
+// or else the highest version that satisfies the range and is not yanked.
+pub fn choose<'a>(state: &'a PackageState, range: &str) -> Option<&'a [u8; 32]> {
+    if let Some(version) = state.tag(range.trim()) {
+        return state.version(version);
+    }
+
+    let requirements = parse_range(range)?;
+    state
+        .versions()
+        .filter(|(version, _)| !state.is_yanked(version))
+        .filter_map(|(version, id)| Some((semver::Version::parse(version).ok()?, id)))
+        .filter(|(version, _)| requirements.iter().any(|req| req.matches(version)))
+        .max_by(|lhs, rhs| lhs.0.cmp(&rhs.0))
+        .map(|(_, id)| id)
+}
+
+async fn load_version<S: ReadableStore>(store: &S, id: &[u8; 32]) -> anyhow::Result<Version> {
+    match store.get(id).await? {
+        Some(envelope) => Version::from_envelope(&envelope),
+        None => bail!("could not find version {}", hex::encode(id)),
+    }
+}
+
+// Picks the version a package spec names, such as one given on the command
+// line.
+pub fn select<A: PackageArg + std::fmt::Display>(
+    packages: &HashMap<String, PackageState>,
+    arg: &A,
+) -> anyhow::Result<[u8; 32]> {
+    let state = match arg.package() {
+        Some(package) => match packages.get(package) {
+            Some(state) => Some(state),
+            None => return Err(ResolveError::NotFound(package.to_string()).into()),
+        },
+        None => None,
+    };
+    match arg.select(state) {
+        Some(id) => Ok(id),
+        None => Err(ResolveError::NoMatch(arg.to_string()).into()),
+    }
+}
+
+// Resolves the dependencies of the version `root` against the packages in
+// `packages`, a map from package name to its state.
+//
+// Dev dependencies are only followed from the root. Peer dependencies are
+// resolved like regular ones. Optional dependencies that cannot be
+// satisfied are left out. Where a name is listed under several kinds, the
+// first in `DependencyKind` order wins. A range may also be any specifier
+// `spec::parse_dependency` accepts, such as "npm:real@^1" or a version id.
+pub async fn resolve<S: ReadableStore>(
+    store: &S,
+    packages: &HashMap<String, PackageState>,
+    root: &[u8; 32],
+) -> anyhow::Result<Graph> {
+    let mut edges = BTreeMap::new();
+    let mut queued = HashSet::new();
+    let mut queue = VecDeque::new();
+    queued.insert(*root);
+    queue.push_back((*root, None));
+
+    while let Some((id, expected)) = queue.pop_front() {
+        let version = load_version(store, &id).await?;
+        if let Some(expected) = expected {
+            if version.name() != expected {
+                bail!(
+                    "{} was published as {} but names itself {}",
+                    hex::encode(id),
+                    expected,
+                    version.name()
+                );
+            }
+        }
+        let parent = format!("{}@{}", version.name(), version.version());
+        let mut resolved = BTreeMap::new();
+        for (name, kind, range) in version.dependencies() {
+            if resolved.contains_key(name) || (*kind == DependencyKind::Dev && id != *root) {
+                continue;
+            }
+            let optional = *kind == DependencyKind::Optional;
+            let unsatisfiable = || ResolveError::Unsatisfiable {
+                name: name.clone(),
+                range: range.clone(),
+                parent: parent.clone(),
+            };
+
+            let specifier = if range.is_empty() {
+                "*"
+            } else {
+                range.as_str()
+            };
+            let arg = match spec::parse_dependency(name, specifier) {
+                Ok(arg) => arg,
+                Err(_) if optional => continue,
+                Err(source) => {
+                    return Err(ResolveError::InvalidDependency {
+                        name: name.clone(),
+                        range: range.clone(),
+                        parent,
+                        source,
+                    }
+                    .into())
+                }
+            };
+
+            let package = arg.package().map(|package| package.to_string());
+            let state = match &package {
+                Some(package) => match packages.get(package) {
+                    Some(state) => Some(state),
+                    None if optional => continue,
+                    None => {
+                        return Err(ResolveError::UnknownPackage {
+                            name: package.clone(),
+                            parent,
+                        }
+                        .into())
+                    }
+                },
+                None => None,
+            };
+
+            let chosen = match arg.select(state) {
+                Some(chosen) => chosen,
+                None if optional => continue,
+                None => return Err(unsatisfiable().into()),
+            };
+            if queued.insert(chosen) {
+                queue.push_back((chosen, package));
+            }
+            resolved.insert(name.clone(), chosen);
+        }
+
+        edges.insert(id, resolved);
+    }
+
+    Ok(Graph { root: *root, edges })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::event::Claim;
+    use crate::objects::version::VersionBuilder;
+    use crate::stores::loose::LooseStore;
+    use crate::stores::WritableStore;
+    use crate::testing::{publication, scratch_store};
+    use sha2::Sha256;
+
+    fn matches(range: &str, version: &str) -> bool {
+        let version = semver::Version::parse(version).unwrap();
+        parse_range(range)
+            .expect("failed to parse range")
+            .iter()
+            .any(|req| req.matches(&version))
+    }
+
+    #[test]
+    fn parse_range_understands_npm_syntax() {
+        assert!(matches("1.2.3", "1.2.3"));
+        assert!(!matches("1.2.3", "1.2.4"));
+        assert!(matches("^1.2.3", "1.9.0"));
+        assert!(matches("~1.2.3", "1.2.9"));
+        assert!(!matches("~1.2.3", "1.3.0"));
+        assert!(matches(">= 1.0.0 < 2", "1.5.0"));
+        assert!(!matches(">= 1.0.0 < 2", "2.0.0"));
+        assert!(matches("1.x", "1.4.0"));
+        assert!(matches("1.2.3 - 2.3", "2.3.9"));
+        assert!(!matches("1.2.3 - 2.3", "2.4.0"));
+        assert!(matches("^1.0.0 || ^3.0.0", "3.1.0"));
+        assert!(matches("*", "4.0.0"));
+        assert!(matches("", "4.0.0"));
+        assert!(parse_range("next").is_none());
+        assert!(parse_range("git+https://example.com/x.git").is_none());
+    }
+
+    async fn add_version(store: &LooseStore<Sha256>, builder: VersionBuilder) -> [u8; 32] {
+        let envelope = builder.build().to_envelope().unwrap();
+        let (id, _) = envelope.content_address::<Sha256>();
+        store.add(envelope).await.unwrap();
+        id
+    }
+
+    async fn registry(
+        store: &LooseStore<Sha256>,
+    ) -> (HashMap<String, PackageState>, Vec<[u8; 32]>) {
+        let mut ids = Vec::new();
+        let mut left = PackageState::default();
+        for version in &["1.0.0", "1.1.0", "1.2.0", "2.0.0"] {
+            let id = add_version(store, VersionBuilder::new("left", version)).await;
+            left.apply_claim(&publication(version, id)).unwrap();
+            ids.push(id);
+        }
+        left.apply_claim(&Claim::Yank {
+            version: "1.2.0".to_string(),
+            reason: "broken".to_string(),
+        })
+        .unwrap();
+
+        let right_id = add_version(
+            store,
+            VersionBuilder::new("right", "1.0.0")
+                .dependency("left", "^1.0.0")
+                .typed_dependency("nowhere", DependencyKind::Dev, "*")
+                .typed_dependency("fsevents", DependencyKind::Optional, "*"),
+        )
+        .await;
+        ids.push(right_id);
+        let mut right = PackageState::default();
+        right.apply_claim(&publication("1.0.0", right_id)).unwrap();
+        right
+            .apply_claim(&Claim::Tag {
+                tag: "stable".to_string(),
+                version: "1.0.0".to_string(),
+            })
+            .unwrap();
+
+        let mut packages = HashMap::new();
+        packages.insert("left".to_string(), left);
+        packages.insert("right".to_string(), right);
+        (packages, ids)
+    }
+
+    #[async_std::test]
+    async fn resolve_picks_highest_unyanked_versions() {
+        let store = scratch_store("resolve-graph");
+        let (packages, ids) = registry(&store).await;
+        let root = add_version(
+            &store,
+            VersionBuilder::new("app", "0.0.0")
+                .dependency("left", ">=2")
+                .dependency("pad", "npm:left@1.0.0")
+                .typed_dependency("right", DependencyKind::Dev, "stable"),
+        )
+        .await;
+
+        let graph = resolve(&store, &packages, &root)
+            .await
+            .expect("failed to resolve");
+        assert_eq!(graph.edges[&root]["left"], ids[3]);
+        assert_eq!(graph.edges[&root]["pad"], ids[0]);
+        assert_eq!(graph.edges[&root]["right"], ids[4]);
+        assert_eq!(graph.edges[&ids[4]]["left"], ids[1]);
+        assert_eq!(graph.edges[&ids[4]].len(), 1);
+        assert_eq!(graph.versions().count(), 5);
+
+        let arg = spec::parse("left@~1.0.0 || 1.1.x").unwrap();
+        assert_eq!(select(&packages, &arg).unwrap(), ids[1]);
+    }
+
+    #[async_std::test]
+    async fn resolve_names_unsatisfiable_ranges() {
+        let store = scratch_store("resolve-unsatisfiable");
+        let (packages, _) = registry(&store).await;
+        let root = add_version(
+            &store,
+            VersionBuilder::new("app", "0.0.0").dependency("left", "~1.2.0"),
+        )
+        .await;
+
+        let err = resolve(&store, &packages, &root).await.unwrap_err();
+        assert_eq!(
+            err.downcast::<ResolveError>().unwrap(),
+            ResolveError::Unsatisfiable {
+                name: "left".to_string(),
+                range: "~1.2.0".to_string(),
+                parent: "app@0.0.0".to_string(),
+            }
+        );
+    }
+
+    #[async_std::test]
+    async fn resolve_reports_dependency_specs_as_written() {
+        let store = scratch_store("resolve-legacy");
+        let (packages, _) = registry(&store).await;
+        let root = add_version(
+            &store,
+            VersionBuilder::new("app", "0.0.0").dependency("JSONStream", "^1.0.0"),
+        )
+        .await;
+        let err = resolve(&store, &packages, &root).await.unwrap_err();
+        assert_eq!(
+            err.downcast::<ResolveError>().unwrap(),
+            ResolveError::UnknownPackage {
+                name: "JSONStream".to_string(),
+                parent: "app@0.0.0".to_string(),
+            }
+        );
+
+        let root = add_version(
+            &store,
+            VersionBuilder::new("app", "0.0.1").dependency("left", "git+https://x/y.git"),
+        )
+        .await;
+        let err = resolve(&store, &packages, &root).await.unwrap_err();
+        assert_eq!(
+            err.downcast::<ResolveError>().unwrap(),
+            ResolveError::InvalidDependency {
+                name: "left".to_string(),
+                range: "git+https://x/y.git".to_string(),
+                parent: "app@0.0.1".to_string(),
+                source: SpecError::UnsupportedProtocol("git+https".to_string()),
+            }
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use crate::envelope::Envelope;
+use crate::objects::version::{check_link, Mode, Version, VersionBuilder};
+use crate::stores::WritableStore;
+use anyhow::{self, bail};
+use chrono::prelude::*;
+use digest::Digest;
+use ignore::gitignore::Gitignore;
+use ignore::Match;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// Names npm leaves out of a package no matter what the ignore files say.
+const ALWAYS_IGNORED: &[&str] = &[
+    ".git",
+    ".svn",
+    ".hg",
+    "CVS",
+    ".npmignore",
+    ".gitignore",
+    ".npmrc",
+    ".DS_Store",
+    "npm-debug.log",
+    "node_modules",
+];
+
+// Like npm, a directory's .npmignore replaces its .gitignore rather than
+// adding to it. Rules from deeper directories take precedence.
+fn ignore_rules(dir: &Path) -> Option<Gitignore> {
+    for name in &[".npmignore", ".gitignore"] {
+        let candidate = dir.join(name);
+        if candidate.is_file() {
+            let (rules, _) = Gitignore::new(candidate);
+            return Some(rules);
+        }
+    }
+    None
+}
+
+fn is_ignored(rules: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    for matcher in rules.iter().rev() {
+        match matcher.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+    }
+    false
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Mode {
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.permissions().mode() & 0o111 != 0 {
+        Mode::Executable
+    } else {
+        Mode::Regular
+    }
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Mode {
+    Mode::Regular
+}
+
+// A symlink is stored as a blob holding its target.
+fn link_target(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let target = fs::read_link(path)?;
+    match target.to_str() {
+        Some(target) => Ok(target.as_bytes().to_vec()),
+        None => bail!("symlink target is not valid utf-8: {:?}", target),
+    }
+}
+
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    rules: &mut Vec<Gitignore>,
+    files: &mut Vec<(String, Mode, PathBuf)>,
+) -> anyhow::Result<()> {
+    let pushed = match ignore_rules(dir) {
+        Some(matcher) => {
+            rules.push(matcher);
+            true
+        }
+        None => false,
+    };
+
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => bail!("path is not valid utf-8: {:?}", name),
+        };
+        if ALWAYS_IGNORED.contains(&name.as_str()) {
+            continue;
+        }
+
+        let path = entry.path();
+        let relative = format!("{}{}", prefix, name);
+        let file_type = entry.file_type()?;
+        if relative == "package.json" {
+            files.push((relative, Mode::Regular, path));
+        } else if file_type.is_dir() {
+            if !is_ignored(&rules[..], &path, true) {
+                walk(&path, &format!("{}/", relative), rules, files)?;
+            }
+        } else if is_ignored(&rules[..], &path, false) {
+            continue;
+        } else if file_type.is_symlink() {
+            files.push((relative, Mode::Symlink, path));
+        } else if file_type.is_file() {
+            files.push((relative, file_mode(&entry.metadata()?), path));
+        }
+    }
+
+    if pushed {
+        rules.pop();
+    }
+    Ok(())
+}
+
+// Snapshots the package rooted at `root` into `store`: every file that
+// survives the .npmignore/.gitignore rules is added as a blob, and the
+// version is built from the root package.json. Executable bits and symlinks
+// are kept; symlinks are not followed. The creation date is the newest mtime
+// among the files. The version itself is not added.
+pub async fn snapshot<D, S, P>(store: &S, root: P) -> anyhow::Result<Version>
+where
+    D: 'static + Digest + Send + Sync,
+    S: WritableStore<D>,
+    P: AsRef<Path>,
+{
+    let root = root.as_ref();
+    let manifest = match fs::read(root.join("package.json")) {
+        Ok(manifest) => manifest,
+        Err(e) => bail!("could not read {:?}: {}", root.join("package.json"), e),
+    };
+    let mut builder = VersionBuilder::from_package_json(&manifest[..])?;
+
+    let mut files = Vec::new();
+    walk(root, "", &mut Vec::new(), &mut files)?;
+
+    let mut created = 0;
+    for (relative, mode, path) in files {
+        let modified = fs::symlink_metadata(&path)?.modified()?;
+        if let Ok(since) = modified.duration_since(UNIX_EPOCH) {
+            created = created.max(since.as_secs());
+        }
+
+        let blob = Envelope::Blob(match mode {
+            Mode::Symlink => {
+                let target = link_target(&path)?;
+                check_link(&relative, std::str::from_utf8(&target[..])?)?;
+                target
+            }
+            _ => async_std::fs::read(&path).await?,
+        });
+        let (id, _) = blob.content_address::<D>();
+        store.add(blob).await?;
+        builder = builder.entry(relative, mode, &id);
+    }
+
+    if let Some(created) = Utc.timestamp_opt(created as i64, 0).single() {
+        builder = builder.created(created);
+    }
+    let version = builder.build();
+    version.check_paths()?;
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch_dir, scratch_store};
+
+    fn write<P: AsRef<Path>>(root: &Path, path: P, data: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    #[async_std::test]
+    async fn snapshot_honours_ignore_files() {
+        let root = scratch_dir("snapshot-root");
+        write(&root, "package.json", r#"{"name":"snap","version":"0.0.1"}"#);
+        write(&root, "index.js", "");
+        write(&root, "debug.log", "");
+        write(&root, ".npmignore", "*.log\ntest/\n");
+        write(&root, ".gitignore", "index.js\n");
+        write(&root, "test/index.js", "");
+        write(&root, "node_modules/dep/index.js", "");
+        write(&root, "lib/.gitignore", "secret.js\n!keep.log\n");
+        write(&root, "lib/secret.js", "");
+        write(&root, "lib/keep.log", "");
+        write(&root, "lib/util.js", "");
+
+        let store = scratch_store("snapshot-store");
+        let version = snapshot(&store, &root).await.expect("failed to snapshot");
+        let paths: Vec<_> = version.paths().iter().map(|(p, _, _)| p.as_str()).collect();
+        assert_eq!(version.name(), "snap");
+        assert_eq!(
+            paths,
+            vec!["index.js", "lib/keep.log", "lib/util.js", "package.json"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn snapshot_keeps_modes_and_symlinks() {
+        use crate::stores::ReadableStore;
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let root = scratch_dir("snapshot-modes");
+        write(&root, "package.json", r#"{"name":"modes","version":"0.0.1"}"#);
+        write(&root, "bin/cli.js", "#!/usr/bin/env node\n");
+        fs::set_permissions(root.join("bin/cli.js"), fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("bin/cli.js", root.join("cli.js")).unwrap();
+
+        let store = scratch_store("snapshot-modes-store");
+        let version = snapshot(&store, &root).await.expect("failed to snapshot");
+        let modes: Vec<_> = version.paths().iter().map(|(p, m, _)| (p.as_str(), *m)).collect();
+        assert_eq!(
+            modes,
+            vec![
+                ("bin/cli.js", Mode::Executable),
+                ("cli.js", Mode::Symlink),
+                ("package.json", Mode::Regular)
+            ]
+        );
+
+        match store.get(version.paths()[1].2).await.unwrap() {
+            Some(Envelope::Blob(bytes)) => assert_eq!(&bytes[..], b"bin/cli.js"),
+            _ => panic!("expected the link target as a blob"),
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use crate::package::PackageState;
+use crate::resolve::{choose, parse_range};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use thiserror::Error;
+
+// Something that names a package version: what it is installed as, which
+// registry package it comes from, and how to pick a version of it.
+pub trait PackageArg {
+    // The name the package is installed under, if the spec gives one.
+    fn name(&self) -> Option<&str>;
+
+    // The registry package whose state `select` needs, or None when the
+    // spec names a version outright.
+    fn package(&self) -> Option<&str>;
+
+    // Picks a version id, given the state of `package()` when there is one.
+    fn select(&self, state: Option<&PackageState>) -> Option<[u8; 32]>;
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SpecError {
+    #[error("package spec is empty")]
+    Empty,
+    #[error("invalid package name {0:?}: {1}")]
+    InvalidName(String, &'static str),
+    #[error("{0:?} has nothing after the \"@\"")]
+    MissingSpecifier(String),
+    #[error("{0:?} is neither a version range nor a tag")]
+    InvalidSpecifier(String),
+    #[error("unsupported specifier protocol {0:?}")]
+    UnsupportedProtocol(String),
+    #[error("alias {0:?} must point at a registry range or tag")]
+    InvalidAliasTarget(String),
+}
+
+// `name` or `name@range`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RangeArg {
+    name: String,
+    range: String,
+}
+
+impl RangeArg {
+    pub fn range(&self) -> &str {
+        &self.range
+    }
+}
+
+impl PackageArg for RangeArg {
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn package(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn select(&self, state: Option<&PackageState>) -> Option<[u8; 32]> {
+        choose(state?, &self.range).cloned()
+    }
+}
+
+// `name` (meaning `name@latest`) or `name@tag`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TagArg {
+    name: String,
+    tag: String,
+}
+
+impl TagArg {
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+}
+
+impl PackageArg for TagArg {
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn package(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn select(&self, state: Option<&PackageState>) -> Option<[u8; 32]> {
+        choose(state?, &self.tag).cloned()
+    }
+}
+
+// A version object id in hex, optionally as `name@<id>`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HashArg {
+    name: Option<String>,
+    id: [u8; 32],
+}
+
+impl HashArg {
+    pub fn id(&self) -> &[u8; 32] {
+        &self.id
+    }
+}
+
+impl PackageArg for HashArg {
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn package(&self) -> Option<&str> {
+        None
+    }
+
+    fn select(&self, _state: Option<&PackageState>) -> Option<[u8; 32]> {
+        Some(self.id)
+    }
+}
+
+// `alias@npm:real@range`: installs `real` under the name `alias`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AliasArg<T: PackageArg + Clone + Debug + Eq + PartialEq> {
+    name: String,
+    package: T,
+}
+
+impl<T: PackageArg + Clone + Debug + Eq + PartialEq> AliasArg<T> {
+    pub fn target(&self) -> &T {
+        &self.package
+    }
+}
+
+impl<T: PackageArg + Clone + Debug + Eq + PartialEq> PackageArg for AliasArg<T> {
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn package(&self) -> Option<&str> {
+        self.package.package()
+    }
+
+    fn select(&self, state: Option<&PackageState>) -> Option<[u8; 32]> {
+        self.package.select(state)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Spec {
+    Range(RangeArg),
+    Tag(TagArg),
+    Hash(HashArg),
+    Alias(Box<AliasArg<Spec>>),
+}
+
+impl PackageArg for Spec {
+    fn name(&self) -> Option<&str> {
+        match self {
+            Spec::Range(arg) => arg.name(),
+            Spec::Tag(arg) => arg.name(),
+            Spec::Hash(arg) => arg.name(),
+            Spec::Alias(arg) => arg.name(),
+        }
+    }
+
+    fn package(&self) -> Option<&str> {
+        match self {
+            Spec::Range(arg) => arg.package(),
+            Spec::Tag(arg) => arg.package(),
+            Spec::Hash(arg) => arg.package(),
+            Spec::Alias(arg) => arg.package(),
+        }
+    }
+
+    fn select(&self, state: Option<&PackageState>) -> Option<[u8; 32]> {
+        match self {
+            Spec::Range(arg) => arg.select(state),
+            Spec::Tag(arg) => arg.select(state),
+            Spec::Hash(arg) => arg.select(state),
+            Spec::Alias(arg) => arg.select(state),
+        }
+    }
+}
+
+impl Display for Spec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Spec::Range(arg) => write!(f, "{}@{}", arg.name, arg.range),
+            Spec::Tag(arg) => write!(f, "{}@{}", arg.name, arg.tag),
+            Spec::Hash(HashArg {
+                name: Some(name),
+                id,
+            }) => write!(f, "{}@{}", name, hex::encode(id)),
+            Spec::Hash(HashArg { name: None, id }) => write!(f, "{}", hex::encode(id)),
+            Spec::Alias(arg) => write!(f, "{}@npm:{}", arg.name, arg.package),
+        }
+    }
+}
+
+fn parse_id(input: &str) -> Option<[u8; 32]> {
+    if input.len() != 64 {
+        return None;
+    }
+    let bytes = hex::decode(input).ok()?;
+    let mut id = [0u8; 32];
+    id.copy_from_slice(&bytes[..]);
+    Some(id)
+}
+
+// The rules npm applies to new package names. `legacy` relaxes them to what
+// npm accepted before: capital letters, long names and the characters
+// "~'!()*" were once allowed, and packages like "JSONStream" still use them.
+fn check_name(name: &str, legacy: bool) -> Result<(), SpecError> {
+    let invalid = |reason| Err(SpecError::InvalidName(name.to_string(), reason));
+    let bare = match name.strip_prefix('@') {
+        Some(scoped) => match scoped.find('/') {
+            Some(idx) if idx > 0 => {
+                if !valid_name_part(&scoped[..idx], legacy) {
+                    return invalid("scope contains characters that are not url-safe");
+                }
+                &scoped[idx + 1..]
+            }
+            _ => return invalid("scoped names look like @scope/name"),
+        },
+        None => name,
+    };
+
+    if bare.is_empty() {
+        invalid("name is empty")
+    } else if !legacy && name.len() > 214 {
+        invalid("name is longer than 214 characters")
+    } else if bare.starts_with('.') || bare.starts_with('_') {
+        invalid("name cannot start with \".\" or \"_\"")
+    } else if !legacy && name.chars().any(|c| c.is_ascii_uppercase()) {
+        invalid("name cannot contain capital letters")
+    } else if !valid_name_part(bare, legacy) {
+        invalid("name contains characters that are not url-safe")
+    } else {
+        Ok(())
+    }
+}
+
+fn valid_name_part(part: &str, legacy: bool) -> bool {
+    part.chars().all(|c| {
+        c.is_ascii_alphanumeric()
+            || matches!(c, '-' | '.' | '_' | '~')
+            || (legacy && matches!(c, '\'' | '!' | '(' | ')' | '*'))
+    })
+}
+
+fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+}
+
+fn parse_target(name: String, specifier: &str) -> Result<Spec, SpecError> {
+    if let Some(id) = parse_id(specifier) {
+        return Ok(Spec::Hash(HashArg {
+            name: Some(name),
+            id,
+        }));
+    }
+    if let Some(idx) = specifier.find(':') {
+        return Err(SpecError::UnsupportedProtocol(specifier[..idx].to_string()));
+    }
+
+    // "latest" parses as a range, but is the default tag.
+    if specifier != "latest" && parse_range(specifier).is_some() {
+        return Ok(Spec::Range(RangeArg {
+            name,
+            range: specifier.to_string(),
+        }));
+    }
+    if valid_tag(specifier) {
+        return Ok(Spec::Tag(TagArg {
+            name,
+            tag: specifier.to_string(),
+        }));
+    }
+    Err(SpecError::InvalidSpecifier(specifier.to_string()))
+}
+
+// Parses a package spec:
+//
+//      name                    the version tagged "latest"
+//      name@1.2.x              the highest version in a range
+//      name@beta               the version a tag points at
+//      @scope/name@^1.0.0      scoped names take any of the above
+//      alias@npm:real@^2       `real`, installed as `alias`
+//      <64 hex digits>         a version object id
+//      name@<64 hex digits>    a version object id, installed as `name`
+pub fn parse<T: AsRef<str>>(spec: T) -> Result<Spec, SpecError> {
+    parse_with(spec.as_ref(), false)
+}
+
+// Parses a dependency as a published version lists it, a name and a range
+// or any other specifier `parse` accepts after the "@". Names are held to
+// npm's older rules, since the dependency was published under them.
+pub fn parse_dependency(name: &str, specifier: &str) -> Result<Spec, SpecError> {
+    parse_with(&format!("{}@{}", name, specifier), true)
+}
+
+fn parse_with(spec: &str, legacy: bool) -> Result<Spec, SpecError> {
+    if spec.is_empty() {
+        return Err(SpecError::Empty);
+    }
+    if let Some(id) = parse_id(spec) {
+        return Ok(Spec::Hash(HashArg { name: None, id }));
+    }
+
+    // A leading "@" belongs to the scope, not the specifier.
+    let skip = if spec.starts_with('@') {
+        '@'.len_utf8()
+    } else {
+        0
+    };
+    let split = spec[skip..].find('@').map(|idx| idx + skip);
+    let (name, specifier) = match split {
+        Some(idx) => (&spec[..idx], Some(&spec[idx + 1..])),
+        None => (spec, None),
+    };
+    check_name(name, legacy)?;
+    let name = name.to_string();
+
+    let specifier = match specifier {
+        None => {
+            return Ok(Spec::Tag(TagArg {
+                name,
+                tag: "latest".to_string(),
+            }))
+        }
+        Some("") => return Err(SpecError::MissingSpecifier(spec.to_string())),
+        Some(specifier) => specifier,
+    };
+
+    match specifier.strip_prefix("npm:") {
+        Some(target) => match parse_with(target, legacy)? {
+            target @ Spec::Range(_) | target @ Spec::Tag(_) => {
+                Ok(Spec::Alias(Box::new(AliasArg {
+                    name,
+                    package: target,
+                })))
+            }
+            _ => Err(SpecError::InvalidAliasTarget(target.to_string())),
+        },
+        None => parse_target(name, specifier),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_accepts_each_kind_of_spec() {
+        let id = "ab".repeat(32);
+        match parse("left-pad").unwrap() {
+            Spec::Tag(arg) => assert_eq!((arg.name(), arg.tag()), (Some("left-pad"), "latest")),
+            other => panic!("expected a tag, got {:?}", other),
+        }
+        match parse("left-pad@1.2.x").unwrap() {
+            Spec::Range(arg) => assert_eq!(arg.range(), "1.2.x"),
+            other => panic!("expected a range, got {:?}", other),
+        }
+        match parse("left-pad@next").unwrap() {
+            Spec::Tag(arg) => assert_eq!(arg.tag(), "next"),
+            other => panic!("expected a tag, got {:?}", other),
+        }
+        match parse("@scope/pkg@^1.0.0").unwrap() {
+            Spec::Range(arg) => assert_eq!(arg.name(), Some("@scope/pkg")),
+            other => panic!("expected a range, got {:?}", other),
+        }
+        let alias = parse("pad@npm:@scope/left-pad@~2.0.0").unwrap();
+        assert_eq!(alias.to_string(), "pad@npm:@scope/left-pad@~2.0.0");
+        match alias {
+            Spec::Alias(arg) => {
+                assert_eq!(arg.name(), Some("pad"));
+                assert_eq!(arg.package(), Some("@scope/left-pad"));
+            }
+            other => panic!("expected an alias, got {:?}", other),
+        }
+        match parse(&id).unwrap() {
+            Spec::Hash(arg) => assert_eq!((arg.name(), arg.id()), (None, &[0xabu8; 32])),
+            other => panic!("expected a hash, got {:?}", other),
+        }
+        match parse(format!("left-pad@{}", id)).unwrap() {
+            Spec::Hash(arg) => assert_eq!(arg.name(), Some("left-pad")),
+            other => panic!("expected a hash, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_reports_precise_errors() {
+        assert_eq!(parse(""), Err(SpecError::Empty));
+        assert!(matches!(
+            parse("Left-Pad"),
+            Err(SpecError::InvalidName(_, _))
+        ));
+        assert!(matches!(parse("@scope"), Err(SpecError::InvalidName(_, _))));
+        assert!(matches!(
+            parse("_private"),
+            Err(SpecError::InvalidName(_, _))
+        ));
+        assert_eq!(
+            parse("left-pad@"),
+            Err(SpecError::MissingSpecifier("left-pad@".to_string()))
+        );
+        assert_eq!(
+            parse("left-pad@>= 1 <"),
+            Err(SpecError::InvalidSpecifier(">= 1 <".to_string()))
+        );
+        assert_eq!(
+            parse("left-pad@git+https://example.com/x.git"),
+            Err(SpecError::UnsupportedProtocol("git+https".to_string()))
+        );
+        assert_eq!(
+            parse("a@npm:b@npm:c@1"),
+            Err(SpecError::InvalidAliasTarget("b@npm:c@1".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_dependency_accepts_legacy_names() {
+        match parse_dependency("JSONStream", "^1.0.0").unwrap() {
+            Spec::Range(arg) => assert_eq!(arg.name(), Some("JSONStream")),
+            other => panic!("expected a range, got {:?}", other),
+        }
+        assert!(parse_dependency("pad", "npm:Left-Pad@1").is_ok());
+        assert!(parse("JSONStream@^1.0.0").is_err());
+        assert!(matches!(
+            parse_dependency("_private", "1"),
+            Err(SpecError::InvalidName(_, _))
+        ));
+        assert_eq!(
+            parse_dependency("left-pad", "git+https://example.com/x.git"),
+            Err(SpecError::UnsupportedProtocol("git+https".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_non_ascii_names_without_panicking() {
+        for spec in &["é", "ü@1.0.0", "@ü/x@1", "€left-pad@latest"] {
+            assert!(
+                matches!(parse(spec), Err(SpecError::InvalidName(_, _))),
+                "{:?}",
+                spec
+            );
+        }
+    }
+}
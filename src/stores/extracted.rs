@@ -0,0 +1,361 @@
+use crate::envelope::Envelope;
+use crate::objects::version::Mode;
+use crate::stores::ReadableStore;
+use anyhow::{self, bail};
+use sha2::Digest;
+use std::fs;
+use std::collections::HashMap;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+// How much older than its last hash a cached file's mtime must be before the
+// hash is taken on trust. Filesystem clocks are coarse, so a file rewritten
+// just after it was hashed can keep the mtime it was hashed with.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+// A directory of blobs written out uncompressed, named by id, so that
+// installs can link to them instead of inflating every file again:
+//
+//      <root>/ab/cdef...       regular files
+//      <root>/ab/cdef...-x     executables
+//      <root>/tmp/             partially written files
+//
+// Executables are kept apart from regular files because hardlinks share
+// permissions. Every file is made read-only, since an installed hardlink
+// that was edited in place would otherwise corrupt the cache for every
+// other project sharing it. Read-only is not tamper-proof, though, so a
+// cached file is hashed before it is first reused, and hashed again whenever
+// its size, mtime or inode no longer match what was hashed.
+pub struct ExtractedBlobs<D> {
+    root: PathBuf,
+    counter: AtomicUsize,
+    verified: Mutex<HashMap<PathBuf, Stamp>>,
+    phantom: PhantomData<D>,
+}
+
+// What a cached file looked like when it was last found intact.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: SystemTime,
+    inode: u64,
+    hashed: SystemTime,
+}
+
+impl Stamp {
+    fn new(metadata: &fs::Metadata) -> Option<Self> {
+        Some(Stamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok()?,
+            inode: inode(metadata),
+            hashed: SystemTime::now(),
+        })
+    }
+
+    // Whether a file with `metadata` is the one this stamp was taken of,
+    // unchanged since.
+    fn matches(&self, metadata: &fs::Metadata) -> bool {
+        let settled = match self.hashed.duration_since(self.modified) {
+            Ok(age) => age >= RACY_WINDOW,
+            Err(_) => false,
+        };
+        settled
+            && metadata.len() == self.len
+            && metadata.modified().ok() == Some(self.modified)
+            && inode(metadata) == self.inode
+    }
+}
+
+impl<D: 'static + Digest + Send + Sync> ExtractedBlobs<D> {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        ExtractedBlobs {
+            root: PathBuf::from(root.as_ref()),
+            counter: AtomicUsize::new(0),
+            verified: Mutex::new(HashMap::new()),
+            phantom: PhantomData,
+        }
+    }
+
+    fn path_for(&self, id: &[u8; 32], mode: Mode) -> PathBuf {
+        let hex = hex::encode(id);
+        let mut path = self.root.join(&hex[0..2]);
+        match mode {
+            Mode::Executable => path.push(format!("{}-x", &hex[2..])),
+            _ => path.push(&hex[2..]),
+        }
+        path
+    }
+
+    // Whether the cached file at `path` still holds `id` with the
+    // permissions it was written with.
+    fn is_intact(&self, path: &Path, id: &[u8; 32], mode: Mode) -> io::Result<bool> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.is_file() || !has_read_only_bits(&metadata, mode) {
+            return Ok(false);
+        }
+        if let Some(stamp) = self.verified.lock().unwrap().get(path) {
+            if stamp.matches(&metadata) {
+                return Ok(true);
+            }
+        }
+
+        let blob = Envelope::Blob(fs::read(path)?);
+        let mut verified = self.verified.lock().unwrap();
+        if blob.content_address::<D>().0 != *id {
+            verified.remove(path);
+            return Ok(false);
+        }
+        match Stamp::new(&metadata) {
+            Some(stamp) => verified.insert(path.to_path_buf(), stamp),
+            None => verified.remove(path),
+        };
+        Ok(true)
+    }
+
+    // Returns the path of the extracted copy of `id`, inflating it from
+    // `store` first if needed. A cached copy that no longer matches `id`
+    // is replaced. Writes go through tmp/ and are renamed into place, so
+    // concurrent installs never see a partial file.
+    pub fn extract_sync<R: ReadableStore>(
+        &self,
+        store: &R,
+        id: &[u8; 32],
+        mode: Mode,
+    ) -> anyhow::Result<PathBuf> {
+        if mode == Mode::Symlink {
+            bail!("symlinks are not extracted");
+        }
+
+        let path = self.path_for(id, mode);
+        match self.is_intact(&path, id, mode) {
+            Ok(true) => return Ok(path),
+            Ok(false) => remove_cached(&path)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => bail!(e),
+        }
+
+        let bytes = match store.get_sync(id)? {
+            Some(Envelope::Blob(bytes)) => bytes,
+            Some(other) => bail!("expected a blob at {}, got a {}", hex::encode(id), other),
+            None => bail!("could not find blob {}", hex::encode(id)),
+        };
+
+        let tmp = self.root.join("tmp");
+        fs::create_dir_all(&tmp)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = tmp.join(format!(
+            "{}-{}-{}",
+            hex::encode(id),
+            std::process::id(),
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, &bytes[..])?;
+        make_read_only(&tmp, mode)?;
+        if let Err(e) = fs::rename(&tmp, &path) {
+            let _ = fs::remove_file(&tmp);
+            bail!(e);
+        }
+        Ok(path)
+    }
+
+    // Installs `id` at `destination` by hardlinking the extracted copy,
+    // falling back to a reflink and then to a plain copy when the cache and
+    // the destination are on different filesystems.
+    pub fn install_sync<R: ReadableStore>(
+        &self,
+        store: &R,
+        id: &[u8; 32],
+        mode: Mode,
+        destination: &Path,
+    ) -> anyhow::Result<()> {
+        let source = self.extract_sync(store, id, mode)?;
+        if fs::hard_link(&source, destination).is_ok() || reflink(&source, destination).is_ok() {
+            return Ok(());
+        }
+
+        // fs::copy carries the read-only permissions across.
+        fs::copy(&source, destination)?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn make_read_only(path: &Path, mode: Mode) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let bits = if mode == Mode::Executable { 0o555 } else { 0o444 };
+    fs::set_permissions(path, fs::Permissions::from_mode(bits))
+}
+
+#[cfg(not(unix))]
+fn make_read_only(path: &Path, _mode: Mode) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(unix)]
+fn has_read_only_bits(metadata: &fs::Metadata, mode: Mode) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    let bits = if mode == Mode::Executable { 0o555 } else { 0o444 };
+    metadata.permissions().mode() & 0o7777 == bits
+}
+
+#[cfg(not(unix))]
+fn has_read_only_bits(metadata: &fs::Metadata, _mode: Mode) -> bool {
+    metadata.permissions().readonly()
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+// Removes a cached file that failed verification. On Windows a read-only
+// file cannot be deleted until it is made writable again.
+#[allow(clippy::permissions_set_readonly_false)]
+fn remove_cached(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        return fs::remove_dir_all(path);
+    }
+    if fs::remove_file(path).is_ok() {
+        return Ok(());
+    }
+    let mut permissions = metadata.permissions();
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)?;
+    fs::remove_file(path)
+}
+
+// Clones `source` into a new file with FICLONE, which shares extents on
+// filesystems that support it (btrfs, xfs) and fails elsewhere.
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+
+    let input = fs::File::open(source)?;
+    let output = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(destination)?;
+    let rc = unsafe { libc::ioctl(output.as_raw_fd(), FICLONE as _, input.as_raw_fd()) };
+    if rc != 0 {
+        let err = io::Error::last_os_error();
+        drop(output);
+        let _ = fs::remove_file(destination);
+        return Err(err);
+    }
+    fs::set_permissions(destination, input.metadata()?.permissions())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _destination: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "reflinks are not supported"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stores::WritableStore;
+    use crate::testing::{scratch_dir, scratch_store};
+    use sha2::Sha256;
+
+    #[async_std::test]
+    async fn install_links_one_extracted_copy() {
+        let store = scratch_store("extracted-store");
+        let blob = Envelope::Blob(b"shared".to_vec());
+        let (id, _) = blob.content_address::<Sha256>();
+        store.add(blob).await.unwrap();
+
+        let blobs = ExtractedBlobs::<Sha256>::new(scratch_dir("extracted-cache"));
+        let projects = scratch_dir("extracted-projects");
+        for name in &["a.js", "b.js"] {
+            blobs
+                .install_sync(&store, &id, Mode::Regular, &projects.join(name))
+                .expect("failed to install");
+        }
+        blobs
+            .install_sync(&store, &id, Mode::Executable, &projects.join("c.js"))
+            .expect("failed to install");
+
+        assert_eq!(fs::read(projects.join("b.js")).unwrap(), b"shared");
+        assert!(fs::metadata(projects.join("a.js")).unwrap().permissions().readonly());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |name: &str| fs::metadata(projects.join(name)).unwrap().ino();
+            assert_eq!(inode("a.js"), inode("b.js"));
+            assert_ne!(inode("a.js"), inode("c.js"));
+        }
+    }
+
+    #[allow(clippy::permissions_set_readonly_false)]
+    fn make_writable(path: &Path) {
+        let mut permissions = fs::metadata(path).unwrap().permissions();
+        permissions.set_readonly(false);
+        fs::set_permissions(path, permissions).unwrap();
+    }
+
+    #[async_std::test]
+    async fn extract_replaces_tampered_copies() {
+        let store = scratch_store("extracted-tampered-store");
+        let blob = Envelope::Blob(b"original".to_vec());
+        let (id, _) = blob.content_address::<Sha256>();
+        store.add(blob).await.unwrap();
+
+        let blobs = ExtractedBlobs::<Sha256>::new(scratch_dir("extracted-tampered-cache"));
+        let path = blobs.extract_sync(&store, &id, Mode::Regular).unwrap();
+
+        // Same length, different contents.
+        make_writable(&path);
+        fs::write(&path, b"tampered").unwrap();
+        make_read_only(&path, Mode::Regular).unwrap();
+        assert_eq!(blobs.extract_sync(&store, &id, Mode::Regular).unwrap(), path);
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+
+        // Right contents, but left writable.
+        make_writable(&path);
+        blobs.extract_sync(&store, &id, Mode::Regular).unwrap();
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+    }
+
+    #[async_std::test]
+    async fn extract_hashes_only_changed_copies() {
+        let store = scratch_store("extracted-stamped-store");
+        let blob = Envelope::Blob(b"original".to_vec());
+        let (id, _) = blob.content_address::<Sha256>();
+        store.add(blob).await.unwrap();
+
+        let blobs = ExtractedBlobs::<Sha256>::new(scratch_dir("extracted-stamped-cache"));
+        let path = blobs.extract_sync(&store, &id, Mode::Regular).unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        let set_modified = |at| fs::File::open(&path).unwrap().set_modified(at).unwrap();
+        set_modified(an_hour_ago);
+        blobs.extract_sync(&store, &id, Mode::Regular).unwrap();
+
+        // A rewrite that keeps the size, mtime and inode goes unnoticed...
+        make_writable(&path);
+        fs::write(&path, b"tampered").unwrap();
+        make_read_only(&path, Mode::Regular).unwrap();
+        set_modified(an_hour_ago);
+        blobs.extract_sync(&store, &id, Mode::Regular).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"tampered");
+
+        // ...but any change to them has the copy hashed again.
+        set_modified(an_hour_ago + Duration::from_secs(1));
+        blobs.extract_sync(&store, &id, Mode::Regular).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+    }
+}
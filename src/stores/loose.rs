@@ -1,4 +1,5 @@
 use crate::envelope::Envelope;
+use crate::stores::packed::packfile_write;
 use crate::stores::{ReadableStore, WritableStore};
 use anyhow::{self, bail};
 use async_std::prelude::*;
@@ -98,31 +99,9 @@ impl<D: 'static + Digest + Send + Sync> LooseStore<D> {
         for hash in &flattened {
             offsets.push(offs);
             let obj = self.get(hash).await?.unwrap();
-            let (typ, bytes) = match &obj {
-                Envelope::Blob(bytes) => (0u8, bytes),
-                Envelope::Event(bytes) => (1u8, bytes),
-                Envelope::Version(bytes) => (2u8, bytes),
-            };
-            let mut size = bytes.len();
-            let mut size_bytes = Vec::new();
-            let first =
-                (typ << 4 | (size & 0xf) as u8 | (if size > 0xf { 0x80 } else { 0x00 })) as u8;
-            size = (size & !0xf) >> 4;
-            size_bytes.push(first);
-            while size > 0 {
-                let next = (size & 0x7f) as u8;
-                size = (size & !0x7f) >> 7;
-                let continuation: u8 = (if size > 0 { 0x80 } else { 0 }) | next;
-                size_bytes.push(continuation);
-            }
-            fd.write_all(&size_bytes[..]).await?;
-            offs += size_bytes.len();
-
-            let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
-            enc.write_all(bytes)?;
-            let finished = &enc.finish()?;
-            offs += finished.len();
-            fd.write_all(finished).await?;
+            let mut entry = Vec::new();
+            offs += packfile_write(&mut entry, &obj)? as usize;
+            fd.write_all(&entry[..]).await?;
         }
 
         // zipper the hashes and offsets together.
@@ -3,9 +3,11 @@ use async_std::stream::Stream;
 use async_trait::async_trait;
 use sha2::Digest;
 
+pub mod extracted;
 pub mod loose;
 pub mod multiple;
 pub mod packed;
+pub mod remote;
 
 // WritableStore
 // - add(Hashable) -> <present | not present>
@@ -1,7 +1,9 @@
 use crate::envelope::Envelope;
-use crate::stores::ReadableStore;
+use crate::stores::{ReadableStore, WritableStore};
 use async_std::stream::Stream;
 use async_trait::async_trait;
+use digest::Digest;
+use std::marker::PhantomData;
 
 pub struct FusedEnvelopeStream;
 
@@ -109,3 +111,75 @@ impl<Reader: ReadableStore + Send + Sync> ReadableStore for Vec<Reader> {
         unimplemented!()
     }
 }
+
+// Reads from `local` first, falling back to `remote`. Objects found in the
+// fallback tier are written to `local` so the next read is served locally.
+pub struct CachingStore<D, L, R> {
+    local: L,
+    remote: R,
+    phantom: PhantomData<D>,
+}
+
+impl<D, L, R> CachingStore<D, L, R> {
+    pub fn new(local: L, remote: R) -> Self {
+        CachingStore {
+            local,
+            remote,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<D, L, R> ReadableStore for CachingStore<D, L, R>
+where
+    D: 'static + Digest + Send + Sync,
+    L: ReadableStore + WritableStore<D> + Send + Sync,
+    R: ReadableStore + Send + Sync,
+{
+    type EnvelopeStream = FusedEnvelopeStream;
+    fn get_sync<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        item: T,
+    ) -> anyhow::Result<Option<Envelope<Vec<u8>>>> {
+        if let Some(obj) = self.local.get_sync(item.as_ref())? {
+            return Ok(Some(obj));
+        }
+
+        match self.remote.get_sync(item.as_ref())? {
+            Some(obj) => {
+                async_std::task::block_on(self.local.add(obj.clone()))?;
+                Ok(Some(obj))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        item: T,
+    ) -> anyhow::Result<Option<Envelope<Vec<u8>>>> {
+        if let Some(obj) = self.local.get(item.as_ref()).await? {
+            return Ok(Some(obj));
+        }
+
+        match self.remote.get(item.as_ref()).await? {
+            Some(obj) => {
+                self.local.add(obj.clone()).await?;
+                Ok(Some(obj))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn list(&self) -> Self::EnvelopeStream {
+        unimplemented!()
+    }
+
+    async fn get_stream<'a, T: AsRef<[u8]> + Send, R2: Stream<Item = &'a [u8]>>(
+        &self,
+        _item: T,
+    ) -> Option<R2> {
+        unimplemented!()
+    }
+}
@@ -6,6 +6,8 @@ use async_trait::async_trait;
 use byteorder::{BigEndian, ReadBytesExt};
 use digest::Digest;
 use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use memmap::{Mmap, MmapOptions};
 use std;
 use std::io::prelude::*;
@@ -139,16 +141,49 @@ impl Reader {
         let mut cursor = Cursor::new(&self.mmap[..end as usize]);
         cursor.seek(SeekFrom::Start(start))?;
 
-        let mut output = Vec::new();
-        let packfile_type = packfile_read(&mut cursor, &mut output, &mut 0)?;
+        packfile_read_object(&mut cursor)
+    }
+}
 
-        Ok(match packfile_type {
-            0 => Envelope::Blob(output),
-            1 => Envelope::Event(output),
-            2 => Envelope::Version(output),
-            _ => bail!("Unrecognized type"),
-        })
+pub fn packfile_read_object<R: BufRead>(input: &mut R) -> anyhow::Result<Envelope<Vec<u8>>> {
+    let mut output = Vec::new();
+    let packfile_type = packfile_read(input, &mut output, &mut 0)?;
+
+    Ok(match packfile_type {
+        0 => Envelope::Blob(output),
+        1 => Envelope::Event(output),
+        2 => Envelope::Version(output),
+        _ => bail!("Unrecognized type"),
+    })
+}
+
+pub fn packfile_write<T: AsRef<[u8]> + Send, W: Write>(
+    output: &mut W,
+    object: &Envelope<T>,
+) -> anyhow::Result<u64> {
+    let (typ, bytes) = match object {
+        Envelope::Blob(bytes) => (0u8, bytes.as_ref()),
+        Envelope::Event(bytes) => (1u8, bytes.as_ref()),
+        Envelope::Version(bytes) => (2u8, bytes.as_ref()),
+    };
+    let mut size = bytes.len();
+    let mut size_bytes = Vec::new();
+    let first = typ << 4 | (size & 0xf) as u8 | (if size > 0xf { 0x80 } else { 0x00 });
+    size = (size & !0xf) >> 4;
+    size_bytes.push(first);
+    while size > 0 {
+        let next = (size & 0x7f) as u8;
+        size = (size & !0x7f) >> 7;
+        let continuation: u8 = (if size > 0 { 0x80 } else { 0 }) | next;
+        size_bytes.push(continuation);
     }
+    output.write_all(&size_bytes[..])?;
+
+    let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+    enc.write_all(bytes)?;
+    let finished = enc.finish()?;
+    output.write_all(&finished[..])?;
+    Ok((size_bytes.len() + finished.len()) as u64)
 }
 
 pub fn packfile_read<R: BufRead, W: Write>(
@@ -0,0 +1,289 @@
+use crate::envelope::Envelope;
+use crate::http::fetch;
+use crate::http::server::MAX_PACK_IDS;
+use crate::stores::multiple::FusedEnvelopeStream;
+use crate::stores::ReadableStore;
+use crate::sync::read_pack_entry;
+use anyhow::{self, bail};
+use async_std::stream::Stream;
+use async_trait::async_trait;
+use digest::Digest;
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+// The most prefetched objects held at once. Objects leave when a `get` takes
+// them; past this many, the oldest are dropped and fetched again if needed.
+const MAX_PREFETCHED: usize = 4096;
+
+#[derive(Default)]
+struct Prefetched {
+    objects: HashMap<Vec<u8>, Envelope<Vec<u8>>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+// A read-only store backed by a remote `eos serve` endpoint. Every object is
+// hashed on arrival and rejected unless its content address matches the id
+// it was requested by.
+pub struct RemoteStore<D> {
+    host: String,
+    prefetched: Mutex<Prefetched>,
+    phantom: PhantomData<D>,
+}
+
+impl<D: 'static + Digest + Send + Sync> RemoteStore<D> {
+    // Takes "host:port" or "http://host:port"; other schemes are refused.
+    pub fn new<T: AsRef<str>>(address: T) -> anyhow::Result<Self> {
+        let address = address.as_ref();
+        let host = match address.find("://") {
+            Some(idx) if &address[..idx] == "http" => &address[idx + 3..],
+            Some(idx) => bail!(
+                "unsupported scheme {:?} in {}: remotes are served over plain http",
+                &address[..idx],
+                address
+            ),
+            None => address,
+        };
+        Ok(RemoteStore {
+            host: host.trim_end_matches('/').to_string(),
+            prefetched: Mutex::new(Prefetched::default()),
+            phantom: PhantomData,
+        })
+    }
+
+    fn verify<T: AsRef<[u8]>>(id: T, object: &Envelope<Vec<u8>>) -> anyhow::Result<()> {
+        let (address, _) = object.content_address::<D>();
+        if &address[..] != id.as_ref() {
+            bail!(
+                "remote object failed verification: requested {}, got {}",
+                hex::encode(id.as_ref()),
+                hex::encode(address)
+            );
+        }
+        Ok(())
+    }
+
+    // Asks the remote which of `ids` it holds.
+    pub async fn has_many(&self, ids: &[[u8; 32]]) -> anyhow::Result<Vec<bool>> {
+        let body: Vec<u8> = ids.iter().flat_map(|id| id.iter().cloned()).collect();
+        let response = fetch(&self.host, "POST", "/has", &body[..]).await?;
+        if response.status != 200 {
+            bail!("remote responded to /has with {}", response.status);
+        }
+        if response.body.len() != ids.len().div_ceil(8) {
+            bail!("remote sent a malformed /has response");
+        }
+        Ok((0..ids.len())
+            .map(|idx| response.body[idx >> 3] & (1 << (idx & 7)) != 0)
+            .collect())
+    }
+
+    // Fetches `ids` and holds them for subsequent `get`s, asking for as few
+    // packs as the remote allows. Returns the number of objects it had.
+    pub async fn prefetch(&self, ids: &[[u8; 32]]) -> anyhow::Result<usize> {
+        let mut count = 0;
+        for batch in ids.chunks(MAX_PACK_IDS) {
+            count += self.prefetch_pack(batch).await?;
+        }
+        Ok(count)
+    }
+
+    async fn prefetch_pack(&self, ids: &[[u8; 32]]) -> anyhow::Result<usize> {
+        let body: Vec<u8> = ids.iter().flat_map(|id| id.iter().cloned()).collect();
+        let response = fetch(&self.host, "POST", "/pack", &body[..]).await?;
+        if response.status != 200 {
+            bail!("remote responded to /pack with {}", response.status);
+        }
+        if response.body.len() < 4 {
+            bail!("remote sent a truncated pack");
+        }
+
+        let mut count_bytes = [0u8; 4];
+        count_bytes.copy_from_slice(&response.body[0..4]);
+        let count = u32::from_be_bytes(count_bytes) as usize;
+        if count > ids.len() {
+            bail!("remote sent {} objects for {} ids", count, ids.len());
+        }
+
+        let mut cursor = &response.body[4..];
+        let mut fetched = HashMap::with_capacity(count);
+        for _ in 0..count {
+            let object = read_pack_entry(&mut cursor).await?;
+            let (address, _) = object.content_address::<D>();
+            if !ids.contains(&address) {
+                bail!("remote sent unrequested object {}", hex::encode(address));
+            }
+            fetched.insert(address.to_vec(), object);
+        }
+
+        let mut prefetched = self.prefetched.lock().unwrap();
+        for (id, object) in fetched {
+            prefetched.order.push_back(id.clone());
+            prefetched.objects.insert(id, object);
+        }
+        while prefetched.order.len() > MAX_PREFETCHED {
+            if let Some(id) = prefetched.order.pop_front() {
+                prefetched.objects.remove(&id);
+            }
+        }
+        Ok(count)
+    }
+}
+
+fn parse_object(body: &[u8]) -> anyhow::Result<Envelope<Vec<u8>>> {
+    let nul = match body.iter().position(|&b| b == 0) {
+        Some(nul) => nul,
+        None => bail!("remote object is missing its header"),
+    };
+    let header = std::str::from_utf8(&body[..nul])?;
+    let (typ, size) = match header.find(' ') {
+        Some(idx) => (&header[..idx], header[idx + 1..].parse::<usize>()?),
+        None => bail!("malformed object header: {:?}", header),
+    };
+
+    let payload = body[nul + 1..].to_vec();
+    if payload.len() != size {
+        bail!(
+            "mismatched len: got {} bytes, expected {}",
+            payload.len(),
+            size
+        )
+    }
+
+    match typ {
+        "blob" => Ok(Envelope::Blob(payload)),
+        "sign" => Ok(Envelope::Event(payload)),
+        "vers" => Ok(Envelope::Version(payload)),
+        _ => bail!("Could not parse object type"),
+    }
+}
+
+#[async_trait]
+impl<D: 'static + Digest + Send + Sync> ReadableStore for RemoteStore<D> {
+    type EnvelopeStream = FusedEnvelopeStream;
+
+    fn get_sync<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        item: T,
+    ) -> anyhow::Result<Option<Envelope<Vec<u8>>>> {
+        async_std::task::block_on(self.get(item))
+    }
+
+    async fn get<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        item: T,
+    ) -> anyhow::Result<Option<Envelope<Vec<u8>>>> {
+        let bytes = item.as_ref();
+        {
+            let mut prefetched = self.prefetched.lock().unwrap();
+            if let Some(object) = prefetched.objects.remove(bytes) {
+                prefetched.order.retain(|id| &id[..] != bytes);
+                return Ok(Some(object));
+            }
+        }
+
+        let path = format!("/objects/{}", hex::encode(bytes));
+        let response = fetch(&self.host, "GET", &path, b"").await?;
+        match response.status {
+            200 => {}
+            404 => return Ok(None),
+            status => bail!("remote responded to {} with {}", path, status),
+        }
+
+        let object = parse_object(&response.body[..])?;
+        Self::verify(bytes, &object)?;
+        Ok(Some(object))
+    }
+
+    async fn list(&self) -> Self::EnvelopeStream {
+        unimplemented!()
+    }
+
+    async fn get_stream<'a, T: AsRef<[u8]> + Send, R: Stream<Item = &'a [u8]>>(
+        &self,
+        _item: T,
+    ) -> Option<R> {
+        unimplemented!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::server::serve;
+    use crate::stores::multiple::CachingStore;
+    use crate::stores::WritableStore;
+    use crate::testing::scratch_store;
+    use async_std::net::TcpListener;
+    use async_std::prelude::*;
+    use async_std::task;
+    use sha2::Sha256;
+    use std::sync::Arc;
+
+    async fn start(name: &str, payloads: &[&[u8]]) -> (String, Vec<[u8; 32]>) {
+        let store = scratch_store(name);
+        let mut ids = Vec::new();
+        for payload in payloads {
+            let blob = Envelope::Blob(payload.to_vec());
+            ids.push(blob.content_address::<Sha256>().0);
+            store.add(blob).await.expect("failed to add blob");
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(serve(listener, Arc::new(store), |_| {}));
+        (format!("http://{}", addr), ids)
+    }
+
+    #[async_std::test]
+    async fn remote_store_fetches_and_prefetches() {
+        let (addr, ids) = start("remote-fetch", &[b"hello", b"world"]).await;
+        let remote = RemoteStore::<Sha256>::new(addr).unwrap();
+        assert!(RemoteStore::<Sha256>::new("https://example.com").is_err());
+
+        match remote.get(ids[0]).await.unwrap() {
+            Some(Envelope::Blob(bytes)) => assert_eq!(&bytes[..], b"hello"),
+            _ => panic!("expected a blob"),
+        }
+        assert!(remote.get([0u8; 32]).await.unwrap().is_none());
+
+        let missing = [0u8; 32];
+        let has = remote.has_many(&[ids[0], missing, ids[1]]).await.unwrap();
+        assert_eq!(has, vec![true, false, true]);
+        assert_eq!(remote.prefetch(&[ids[0], missing, ids[1]]).await.unwrap(), 2);
+        assert!(remote.prefetched.lock().unwrap().objects.contains_key(&ids[1][..]));
+        assert!(remote.get(ids[0]).await.unwrap().is_some());
+        assert!(remote.get(ids[1]).await.unwrap().is_some());
+        assert!(remote.prefetched.lock().unwrap().objects.is_empty());
+        assert!(remote.prefetched.lock().unwrap().order.is_empty());
+    }
+
+    #[async_std::test]
+    async fn remote_store_rejects_mismatched_objects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = b"blob 4\0evil";
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&body[..]).await.unwrap();
+        });
+
+        let remote = RemoteStore::<Sha256>::new(addr.to_string()).unwrap();
+        assert!(remote.get([1u8; 32]).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn caching_store_writes_through_to_local() {
+        let (addr, ids) = start("remote-cache-origin", &[b"cached"]).await;
+        let local = scratch_store("remote-cache-local");
+        let store = CachingStore::new(local.clone(), RemoteStore::<Sha256>::new(addr).unwrap());
+
+        assert!(local.get(ids[0]).await.unwrap().is_none());
+        assert!(store.get(ids[0]).await.unwrap().is_some());
+        assert!(local.get(ids[0]).await.unwrap().is_some());
+    }
+}
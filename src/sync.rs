@@ -0,0 +1,416 @@
+use crate::envelope::Envelope;
+use crate::objects::event::{Claim, Event};
+use crate::objects::version::Version;
+use crate::stores::packed::{packfile_read_object, packfile_write};
+use crate::stores::{ReadableStore, WritableStore};
+use anyhow::{self, bail};
+use async_std::io::prelude::*;
+use async_std::io::{Read, Write};
+use sha2::Digest;
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+
+// The sync protocol is a conversation between a sender, holding heads (event
+// ids) that the receiver may lack, and a receiver. It runs over any pair of
+// byte streams: pipes, ssh, or a socketpair.
+//
+// sender -> receiver: HEADS   ids the sender wants to transfer
+// receiver -> sender: HAVE    ids the receiver holds: its own heads, plus any
+//                             of the sender's heads it already has
+// sender -> receiver: QUERY   the next generation of ids the sender would send
+// receiver -> sender: ANSWER  a bitmap, one bit per queried id, set if present
+//   ... QUERY/ANSWER repeat until the sender runs out of ids ...
+// sender -> receiver: PACK    the missing objects as packfile entries, each
+//                             prefixed with a 4 byte big-endian length
+// receiver -> sender: DONE    the number of objects the receiver wrote
+//
+// Every frame starts with a tag byte and a 4 byte big-endian count. Frames of
+// ids carry at most MAX_FRAME_IDS of them, so a peer cannot make the other
+// side allocate more than that up front; longer queries are split.
+//
+// Both sides assume that holding an object means holding everything it
+// refers to, so the sender stops walking as soon as the receiver answers
+// that it has an id. Objects are packed so that referenced objects arrive
+// before the objects that refer to them, which keeps that assumption true
+// if the transfer is interrupted.
+const FRAME_HEADS: u8 = b'H';
+const FRAME_HAVE: u8 = b'V';
+const FRAME_QUERY: u8 = b'Q';
+const FRAME_ANSWER: u8 = b'A';
+const FRAME_PACK: u8 = b'P';
+const FRAME_DONE: u8 = b'D';
+const MAX_FRAME_IDS: usize = 1 << 20;
+
+async fn write_frame_header<W: Write + Unpin>(
+    output: &mut W,
+    tag: u8,
+    count: usize,
+) -> anyhow::Result<()> {
+    output.write_all(&[tag]).await?;
+    output.write_all(&(count as u32).to_be_bytes()).await?;
+    Ok(())
+}
+
+async fn read_frame_header<R: Read + Unpin>(input: &mut R, expect: u8) -> anyhow::Result<usize> {
+    let mut header = [0u8; 5];
+    input.read_exact(&mut header).await?;
+    if header[0] != expect {
+        bail!(
+            "unexpected frame: got {:?}, expected {:?}",
+            header[0] as char,
+            expect as char
+        );
+    }
+    let mut count = [0u8; 4];
+    count.copy_from_slice(&header[1..]);
+    Ok(u32::from_be_bytes(count) as usize)
+}
+
+async fn write_ids<W: Write + Unpin>(
+    output: &mut W,
+    tag: u8,
+    ids: &[[u8; 32]],
+) -> anyhow::Result<()> {
+    write_frame_header(output, tag, ids.len()).await?;
+    for id in ids {
+        output.write_all(&id[..]).await?;
+    }
+    output.flush().await?;
+    Ok(())
+}
+
+fn check_id_count(count: usize) -> anyhow::Result<()> {
+    if count > MAX_FRAME_IDS {
+        bail!("frame lists {} ids, more than the {} allowed", count, MAX_FRAME_IDS);
+    }
+    Ok(())
+}
+
+async fn read_ids<R: Read + Unpin>(input: &mut R, expect: u8) -> anyhow::Result<Vec<[u8; 32]>> {
+    let count = read_frame_header(input, expect).await?;
+    check_id_count(count)?;
+    let mut ids = Vec::with_capacity(count);
+    while ids.len() < count {
+        let mut id = [0u8; 32];
+        input.read_exact(&mut id).await?;
+        ids.push(id);
+    }
+    Ok(ids)
+}
+
+// Pack streams carry each object as a packfile entry prefixed with its
+// length, so the reader can find entry boundaries without inflating. The
+// length is the sender's word, so entries are read as they arrive rather
+// than into a buffer of that size.
+pub async fn write_pack_entry<W, T>(output: &mut W, object: &Envelope<T>) -> anyhow::Result<()>
+where
+    W: Write + Unpin,
+    T: AsRef<[u8]> + Send,
+{
+    let mut entry = Vec::new();
+    packfile_write(&mut entry, object)?;
+    output.write_all(&(entry.len() as u32).to_be_bytes()).await?;
+    output.write_all(&entry[..]).await?;
+    Ok(())
+}
+
+pub async fn read_pack_entry<R: Read + Unpin>(input: &mut R) -> anyhow::Result<Envelope<Vec<u8>>> {
+    let mut len_bytes = [0u8; 4];
+    input.read_exact(&mut len_bytes).await?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    let mut entry = Vec::new();
+    input.take(len as u64).read_to_end(&mut entry).await?;
+    if entry.len() != len {
+        bail!("pack entry ended after {} of {} bytes", entry.len(), len);
+    }
+    packfile_read_object(&mut Cursor::new(&entry[..]))
+}
+
+// Lists the ids an object refers to. Events refer to their parents and to the
+// objects named by their publications; versions refer to their blobs.
+pub fn references<T: AsRef<[u8]> + Send>(object: &Envelope<T>) -> anyhow::Result<Vec<[u8; 32]>> {
+    match object {
+        Envelope::Event(bytes) => {
+            let event = Event::from_bytes(bytes.as_ref())?;
+            let mut refs = event.parents().to_vec();
+            for claim in event.claims() {
+                if let Claim::Publication { id, .. } = claim {
+                    if id.len() == 32 {
+                        let mut oid = [0u8; 32];
+                        oid.copy_from_slice(&id[..]);
+                        refs.push(oid);
+                    }
+                }
+            }
+            Ok(refs)
+        }
+        Envelope::Version(_) => Ok(Version::from_envelope(object)?
+            .paths()
+            .iter()
+            .map(|(_, _, id)| *id)
+            .collect()),
+        Envelope::Blob(_) => Ok(Vec::new()),
+    }
+}
+
+// Collects every event reachable from `heads` that is present in `store`.
+async fn ancestors<S: ReadableStore>(
+    store: &S,
+    heads: &[[u8; 32]],
+) -> anyhow::Result<HashSet<[u8; 32]>> {
+    let mut seen = HashSet::new();
+    let mut pending = heads.to_vec();
+    while let Some(id) = pending.pop() {
+        if seen.contains(&id) {
+            continue;
+        }
+        if let Some(Envelope::Event(bytes)) = store.get(id).await? {
+            seen.insert(id);
+            pending.extend(Event::from_bytes(bytes)?.parents());
+        }
+    }
+    Ok(seen)
+}
+
+// Orders the objects in `refs`, a map from each object to the ids it refers
+// to, so that every object comes after the objects it refers to. Walks from
+// `heads` depth first and places an object once everything below it is
+// placed; ids that are not keys of `refs` are not being sent.
+fn referents_first(
+    heads: &[[u8; 32]],
+    refs: &HashMap<[u8; 32], Vec<[u8; 32]>>,
+) -> Vec<[u8; 32]> {
+    let mut placed = HashSet::new();
+    let mut order = Vec::with_capacity(refs.len());
+    let mut pending: Vec<_> = heads.iter().rev().map(|id| (*id, false)).collect();
+    while let Some((id, expanded)) = pending.pop() {
+        let references = match refs.get(&id) {
+            Some(references) => references,
+            None => continue,
+        };
+        if !placed.contains(&id) {
+            if expanded {
+                placed.insert(id);
+                order.push(id);
+            } else {
+                pending.push((id, true));
+                for reference in references.iter().rev() {
+                    if !placed.contains(reference) {
+                        pending.push((*reference, false));
+                    }
+                }
+            }
+        }
+    }
+    order
+}
+
+pub async fn send<R, W, S>(
+    input: &mut R,
+    output: &mut W,
+    store: &S,
+    heads: &[[u8; 32]],
+) -> anyhow::Result<usize>
+where
+    R: Read + Unpin,
+    W: Write + Unpin,
+    S: ReadableStore,
+{
+    write_ids(output, FRAME_HEADS, heads).await?;
+    let have = read_ids(input, FRAME_HAVE).await?;
+    let common = ancestors(store, &have[..]).await?;
+
+    let mut seen: HashSet<[u8; 32]> = common.iter().cloned().collect();
+    seen.extend(have.iter().cloned());
+
+    let mut generation = Vec::new();
+    for head in heads {
+        if seen.insert(*head) {
+            generation.push(*head);
+        }
+    }
+
+    let mut missing = HashMap::new();
+    let mut refs = HashMap::new();
+    while !generation.is_empty() {
+        let mut next = Vec::new();
+        for query in generation.chunks(MAX_FRAME_IDS) {
+            write_ids(output, FRAME_QUERY, query).await?;
+            let count = read_frame_header(input, FRAME_ANSWER).await?;
+            if count != query.len() {
+                bail!(
+                    "receiver answered for {} ids, expected {}",
+                    count,
+                    query.len()
+                );
+            }
+            let mut bitmap = vec![0u8; count.div_ceil(8)];
+            input.read_exact(&mut bitmap[..]).await?;
+
+            for (idx, id) in query.iter().enumerate() {
+                if bitmap[idx >> 3] & (1 << (idx & 7)) != 0 {
+                    continue;
+                }
+
+                let object = match store.get(id).await? {
+                    Some(object) => object,
+                    None => bail!("missing object {}", hex::encode(id)),
+                };
+
+                let object_refs = references(&object)?;
+                for reference in object_refs.iter() {
+                    if seen.insert(*reference) {
+                        next.push(*reference);
+                    }
+                }
+                refs.insert(*id, object_refs);
+                missing.insert(*id, object);
+            }
+        }
+        generation = next;
+    }
+
+    let order = referents_first(heads, &refs);
+    write_frame_header(output, FRAME_PACK, order.len()).await?;
+    for id in order.iter() {
+        write_pack_entry(output, &missing[id]).await?;
+    }
+    output.flush().await?;
+
+    let written = read_frame_header(input, FRAME_DONE).await?;
+    if written > missing.len() {
+        bail!(
+            "receiver reported {} objects written, but only {} were sent",
+            written,
+            missing.len()
+        );
+    }
+    Ok(missing.len())
+}
+
+pub async fn receive<D, R, W, S, Dest>(
+    input: &mut R,
+    output: &mut W,
+    store: &S,
+    destination: &Dest,
+    heads: &[[u8; 32]],
+) -> anyhow::Result<usize>
+where
+    D: 'static + Digest + Send + Sync,
+    R: Read + Unpin,
+    W: Write + Unpin,
+    S: ReadableStore,
+    Dest: WritableStore<D>,
+{
+    let theirs = read_ids(input, FRAME_HEADS).await?;
+    let mut have = heads.to_vec();
+    for id in theirs {
+        if store.get(id).await?.is_some() {
+            have.push(id);
+        }
+    }
+    write_ids(output, FRAME_HAVE, &have[..]).await?;
+
+    let mut wanted = HashSet::new();
+    let count = loop {
+        let mut header = [0u8; 5];
+        input.read_exact(&mut header).await?;
+        let mut count_bytes = [0u8; 4];
+        count_bytes.copy_from_slice(&header[1..]);
+        let count = u32::from_be_bytes(count_bytes) as usize;
+
+        match header[0] {
+            FRAME_QUERY => {
+                check_id_count(count)?;
+                let mut bitmap = vec![0u8; count.div_ceil(8)];
+                for idx in 0..count {
+                    let mut id = [0u8; 32];
+                    input.read_exact(&mut id).await?;
+                    if store.get(id).await?.is_some() {
+                        bitmap[idx >> 3] |= 1 << (idx & 7);
+                    } else {
+                        wanted.insert(id);
+                    }
+                }
+                write_frame_header(output, FRAME_ANSWER, count).await?;
+                output.write_all(&bitmap[..]).await?;
+                output.flush().await?;
+            }
+            FRAME_PACK => break count,
+            tag => bail!("unexpected frame: got {:?}", tag as char),
+        }
+    };
+
+    let mut written = 0;
+    for _ in 0..count {
+        let object = read_pack_entry(input).await?;
+        let (id, _) = object.content_address::<D>();
+        if !wanted.remove(&id) {
+            bail!("received unrequested object {}", hex::encode(id));
+        }
+        if destination.add(object).await? {
+            written += 1;
+        }
+    }
+
+    write_frame_header(output, FRAME_DONE, written).await?;
+    output.flush().await?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::event::EventBuilder;
+    use async_std::os::unix::net::UnixStream;
+    use crate::testing::{add_event, authority, scratch_store};
+    use sodiumoxide::crypto::sign;
+
+    #[async_std::test]
+    async fn sync_sends_only_missing_objects() {
+        let sender_store = scratch_store("sender");
+        let receiver_store = scratch_store("receiver");
+
+        let (pk, sk) = sign::gen_keypair();
+        let owner = authority("test", &pk);
+        let root = add_event(&sender_store, &sk, 1000, EventBuilder::new().claim(owner)).await;
+        let middle = add_event(&sender_store, &sk, 2000, EventBuilder::new().parent(root)).await;
+        let head = add_event(&sender_store, &sk, 3000, EventBuilder::new().parent(middle)).await;
+
+        let root_envelope = sender_store.get(root).await.unwrap().unwrap();
+        receiver_store.add(root_envelope).await.unwrap();
+
+        let (heads, have) = ([head], [root]);
+        let (left, right) = UnixStream::pair().expect("failed to create socketpair");
+        let (mut sender_in, mut sender_out) = (&left, &left);
+        let (mut receiver_in, mut receiver_out) = (&right, &right);
+        let (sent, received) = futures::join!(
+            send(&mut sender_in, &mut sender_out, &sender_store, &heads),
+            receive(
+                &mut receiver_in,
+                &mut receiver_out,
+                &receiver_store,
+                &receiver_store,
+                &have,
+            )
+        );
+
+        assert_eq!(sent.expect("send failed"), 2);
+        assert_eq!(received.expect("receive failed"), 2);
+        assert!(receiver_store.get(head).await.unwrap().is_some());
+        assert!(receiver_store.get(middle).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn pack_order_puts_referents_first() {
+        // a refers to c and b, and b to c, so c must come before b even
+        // though both were found in the same generation.
+        let (a, b, c, d) = ([1; 32], [2; 32], [3; 32], [4; 32]);
+        let mut refs = HashMap::new();
+        refs.insert(a, vec![c, b, d]);
+        refs.insert(b, vec![c, d]);
+        refs.insert(c, vec![]);
+        assert_eq!(referents_first(&[a], &refs), vec![c, b, a]);
+        assert_eq!(referents_first(&[b, a], &refs), vec![c, b, a]);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::envelope::Envelope;
+use crate::objects::version::{check_link, Mode, Version, VersionBuilder};
+use crate::stores::{ReadableStore, WritableStore};
+use anyhow::{self, bail};
+use chrono::prelude::*;
+use digest::Digest;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path};
+use tar::{Archive, Builder, EntryType, Header};
+
+// npm tarballs wrap the package in a single leading directory, almost always
+// "package/". Like npm, we drop the first component whatever its name.
+fn package_path(path: &Path) -> anyhow::Result<Option<String>> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => match part.to_str() {
+                Some(part) => parts.push(part),
+                None => bail!("tarball path is not valid utf-8: {:?}", path),
+            },
+            Component::CurDir => {}
+            _ => bail!("unsafe path in tarball: {:?}", path),
+        }
+    }
+
+    if parts.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(parts[1..].join("/")))
+}
+
+// Streams a gzipped npm tarball into `store`, writing each file as a blob and
+// the resulting path -> blob map as a version. Returns the version id.
+//
+// Regular files and symlinks are imported; a file with any execute bit set is
+// recorded as executable, and a symlink's target is stored as its blob. A
+// symlink that points outside the package fails the import.
+// Directories are implied by the paths of the files inside them, and hard
+// links and devices are skipped. The name, version and dependencies come
+// from package.json; the creation date is the newest mtime in the tarball.
+pub async fn import<D, S, R>(store: &S, input: R) -> anyhow::Result<[u8; 32]>
+where
+    D: 'static + Digest + Send + Sync,
+    S: WritableStore<D>,
+    R: Read,
+{
+    let mut archive = Archive::new(GzDecoder::new(input));
+    let mut paths = BTreeMap::new();
+    let mut manifest = None;
+    let mut created = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let mode = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                if entry.header().mode()? & 0o111 != 0 {
+                    Mode::Executable
+                } else {
+                    Mode::Regular
+                }
+            }
+            EntryType::Symlink => Mode::Symlink,
+            _ => continue,
+        };
+
+        let path = match package_path(&entry.path()?)? {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let data = if mode == Mode::Symlink {
+            match entry.link_name()? {
+                Some(target) => match target.to_str() {
+                    Some(target) => {
+                        check_link(&path, target)?;
+                        target.as_bytes().to_vec()
+                    }
+                    None => bail!("symlink target is not valid utf-8: {:?}", target),
+                },
+                None => bail!("symlink {:?} has no target", path),
+            }
+        } else {
+            // The header's size is not trusted for an allocation up front.
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            data
+        };
+        created = created.max(entry.header().mtime()?);
+        if path == "package.json" && mode != Mode::Symlink {
+            manifest = Some(VersionBuilder::from_package_json(&data[..])?);
+        }
+        let blob = Envelope::Blob(data);
+        let (id, _) = blob.content_address::<D>();
+        store.add(blob).await?;
+
+        // Later entries win, matching what extracting the tarball would do.
+        paths.insert(path, (mode, id));
+    }
+
+    let builder = match manifest {
+        Some(builder) => builder,
+        None => bail!("tarball does not contain a package.json"),
+    };
+    let created = match Utc.timestamp_opt(created as i64, 0).single() {
+        Some(created) => created,
+        None => bail!("tarball mtime is out of range"),
+    };
+    let mut builder = builder.created(created);
+    for (path, (mode, id)) in paths {
+        builder = builder.entry(path, mode, &id);
+    }
+
+    let version = builder.build();
+    version.check_paths()?;
+    let envelope = version.to_envelope()?;
+    let (id, _) = envelope.content_address::<D>();
+    store.add(envelope).await?;
+    Ok(id)
+}
+
+// Writes the version named by `id` as a gzipped npm tarball. The output
+// depends only on the version: entries are written in path order under
+// "package/", with 0644, 0755 or 0777 modes for regular files, executables
+// and symlinks, fixed owners, every mtime set to the version's creation
+// date, and a gzip header that carries no timestamp or filename.
+pub async fn export<S, T, W>(store: &S, id: T, output: W) -> anyhow::Result<W>
+where
+    S: ReadableStore,
+    T: AsRef<[u8]> + Send + Sync,
+    W: Write,
+{
+    let version = match store.get(id.as_ref()).await? {
+        Some(envelope) => Version::from_envelope(&envelope)?,
+        None => bail!("could not find version {}", hex::encode(id.as_ref())),
+    };
+    let mtime = version.created().timestamp().max(0) as u64;
+
+    let mut builder = Builder::new(GzEncoder::new(output, Compression::default()));
+    for (path, mode, blob_id) in version.paths() {
+        let data = match store.get(blob_id).await? {
+            Some(Envelope::Blob(bytes)) => bytes,
+            Some(_) => bail!("{} is not a blob", hex::encode(blob_id)),
+            None => bail!("could not find blob {} for {}", hex::encode(blob_id), path),
+        };
+
+        let mut header = Header::new_ustar();
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_username("")?;
+        header.set_groupname("")?;
+        let path = format!("package/{}", path);
+        match mode {
+            Mode::Symlink => {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                header.set_mode(0o777);
+                let target = std::str::from_utf8(&data[..])?;
+                builder.append_link(&mut header, path, target)?;
+            }
+            _ => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(data.len() as u64);
+                header.set_mode(if *mode == Mode::Executable { 0o755 } else { 0o644 });
+                builder.append_data(&mut header, path, &data[..])?;
+            }
+        }
+    }
+
+    Ok(builder.into_inner()?.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::version::{DependencyKind, PathError};
+    use crate::testing::scratch_store;
+
+    fn append(builder: &mut Builder<GzEncoder<Vec<u8>>>, path: &str, typ: EntryType, data: &[u8]) {
+        let mode = if path.contains("/bin/") { 0o755 } else { 0o644 };
+        let mut header = Header::new_gnu();
+        header.set_entry_type(typ);
+        header.set_size(data.len() as u64);
+        header.set_mode(mode);
+        header.set_mtime(1_500_000_000);
+        builder
+            .append_data(&mut header, path, data)
+            .expect("failed to append entry");
+    }
+
+    fn fixture() -> Vec<u8> {
+        fixture_linking_to("lib/index.js")
+    }
+
+    fn fixture_linking_to(target: &str) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        append(
+            &mut builder,
+            "package/package.json",
+            EntryType::Regular,
+            br#"{"name":"hi","version":"1.0.0","dependencies":{"left-pad":"^1.0.0"},"peerDependencies":{"react":">=16"}}"#,
+        );
+        append(&mut builder, "package/lib", EntryType::Directory, b"");
+        append(&mut builder, "package/lib/index.js", EntryType::Regular, b"hi");
+        append(&mut builder, "package/README.md", EntryType::Regular, b"# hi");
+        append(&mut builder, "package/bin/hi", EntryType::Regular, b"#!/bin/sh");
+
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_mtime(1_500_000_000);
+        builder
+            .append_link(&mut header, "package/index.js", target)
+            .expect("failed to append link");
+        builder
+            .into_inner()
+            .and_then(|gz| gz.finish())
+            .expect("failed to build tarball")
+    }
+
+    #[async_std::test]
+    async fn import_builds_version_from_tarball() {
+        let tarball = fixture();
+        let store = scratch_store("tarball-import");
+        let id = import(&store, &tarball[..]).await.expect("failed to import");
+
+        let envelope = store.get(id).await.unwrap().expect("missing version");
+        let version = Version::from_envelope(&envelope).unwrap();
+        assert_eq!(version.name(), "hi");
+        assert_eq!(version.version(), "1.0.0");
+        assert_eq!(version.created().timestamp(), 1_500_000_000);
+        assert_eq!(version.dependencies()[0].0, "left-pad");
+        assert_eq!(version.dependencies()[1].1, DependencyKind::Peer);
+        let paths: Vec<_> = version.paths().iter().map(|(p, m, _)| (p.as_str(), *m)).collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("README.md", Mode::Regular),
+                ("bin/hi", Mode::Executable),
+                ("index.js", Mode::Symlink),
+                ("lib/index.js", Mode::Regular),
+                ("package.json", Mode::Regular)
+            ]
+        );
+
+        let (_, _, blob) = &version.paths()[3];
+        match store.get(blob).await.unwrap() {
+            Some(Envelope::Blob(bytes)) => assert_eq!(&bytes[..], b"hi"),
+            _ => panic!("expected a blob"),
+        }
+    }
+
+    #[async_std::test]
+    async fn import_rejects_escaping_symlinks() {
+        let store = scratch_store("tarball-escape");
+        let err = import(&store, &fixture_linking_to("../../.ssh/id_rsa")[..])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<PathError>().ok(),
+            Some(PathError::LinkEscapes(
+                "index.js".to_string(),
+                "../../.ssh/id_rsa".to_string()
+            ))
+        );
+    }
+
+    #[async_std::test]
+    async fn export_is_reproducible() {
+        let store = scratch_store("tarball-export");
+        let id = import(&store, &fixture()[..]).await.expect("failed to import");
+
+        let first = export(&store, id, Vec::new()).await.expect("failed to export");
+        let second = export(&store, id, Vec::new()).await.expect("failed to export");
+        assert_eq!(first, second);
+
+        let reimported = import(&store, &first[..]).await.expect("failed to reimport");
+        assert_eq!(reimported, id);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::envelope::Envelope;
+use crate::objects::event::{Claim, EventBuilder};
+use crate::stores::loose::LooseStore;
+use crate::stores::WritableStore;
+use chrono::prelude::*;
+use sha2::Sha256;
+use sodiumoxide::crypto::sign::ed25519::{PublicKey, SecretKey};
+use std::path::PathBuf;
+
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("eos-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("failed to create scratch dir");
+    dir
+}
+
+pub(crate) fn scratch_store(name: &str) -> LooseStore<Sha256> {
+    let dir = scratch_dir(name);
+    let mut tmp = dir.clone();
+    tmp.push("tmp");
+    std::fs::create_dir_all(&tmp).expect("failed to create scratch store");
+    LooseStore::new(dir)
+}
+
+// Signs `builder` as "test", dated `at` seconds into the epoch, and adds the
+// event to `store`.
+pub(crate) async fn add_event(
+    store: &LooseStore<Sha256>,
+    sk: &SecretKey,
+    at: i64,
+    builder: EventBuilder,
+) -> [u8; 32] {
+    add_event_as(store, "test", sk, at, builder).await
+}
+
+pub(crate) async fn add_event_as(
+    store: &LooseStore<Sha256>,
+    signatory: &str,
+    sk: &SecretKey,
+    at: i64,
+    builder: EventBuilder,
+) -> [u8; 32] {
+    let event = builder
+        .at(Utc.timestamp_opt(at, 0).unwrap())
+        .sign(signatory, sk, store)
+        .expect("failed to sign");
+    let mut buf = Vec::new();
+    event.to_bytes(&mut buf).expect("failed to encode");
+    add_event_bytes(store, buf).await
+}
+
+// Adds an encoded event as is, for events `EventBuilder::sign` would refuse.
+pub(crate) async fn add_event_bytes(store: &LooseStore<Sha256>, buf: Vec<u8>) -> [u8; 32] {
+    let envelope = Envelope::Event(buf);
+    let (id, _) = envelope.content_address::<Sha256>();
+    store.add(envelope).await.expect("failed to add");
+    id
+}
+
+pub(crate) fn authority(name: &str, public_key: &PublicKey) -> Claim {
+    Claim::AuthorityAdd {
+        public_key: hex::encode(public_key),
+        name: name.to_string(),
+    }
+}
+
+pub(crate) fn publication(version: &str, id: [u8; 32]) -> Claim {
+    Claim::Publication {
+        version: version.to_string(),
+        id: id.to_vec(),
+    }
+}
@@ -0,0 +1,292 @@
+use crate::envelope::Envelope;
+use crate::objects::version::{check_path, install_sync, Mode, Version};
+use crate::stores::ReadableStore;
+use anyhow::{self, bail};
+use digest::Digest;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+// How an unpacked directory differs from its version. Paths are relative to
+// the directory; an extra path ending in "/" is a whole directory the
+// version knows nothing about.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub modified: Vec<String>,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.modified.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+// Hashes a file the way `Envelope::content_address` hashes a blob, without
+// reading it into memory all at once.
+fn hash_file<D: Digest>(path: &Path, len: u64) -> anyhow::Result<[u8; 32]> {
+    let mut digest = D::new();
+    digest.input(format!("blob {}\0", len));
+    let mut file = fs::File::open(path)?;
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.input(&buf[..n]);
+    }
+
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&digest.result()[..]);
+    Ok(bytes)
+}
+
+fn hash_link<D: 'static + Digest + Send + Sync>(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let target = fs::read_link(path)?;
+    match target.to_str() {
+        Some(target) => Ok(Envelope::Blob(target.as_bytes()).content_address::<D>().0),
+        None => bail!("symlink target is not valid utf-8: {:?}", target),
+    }
+}
+
+// Executable bits only exist on unix; elsewhere any file satisfies either.
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata, _expected: Mode) -> Mode {
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.permissions().mode() & 0o111 != 0 {
+        Mode::Executable
+    } else {
+        Mode::Regular
+    }
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata, expected: Mode) -> Mode {
+    match expected {
+        Mode::Symlink => Mode::Regular,
+        mode => mode,
+    }
+}
+
+struct Walk<'a> {
+    expected: HashMap<&'a str, (Mode, [u8; 32])>,
+    directories: HashSet<&'a str>,
+    seen: HashSet<String>,
+    report: Report,
+}
+
+impl<'a> Walk<'a> {
+    fn walk<D: 'static + Digest + Send + Sync>(
+        &mut self,
+        dir: &Path,
+        prefix: &str,
+    ) -> anyhow::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => bail!("path is not valid utf-8: {:?}", name),
+            };
+            let relative = format!("{}{}", prefix, name);
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path)?;
+
+            let expected = match self.expected.get(relative.as_str()) {
+                Some(expected) => *expected,
+                None if metadata.is_dir() && self.directories.contains(relative.as_str()) => {
+                    self.walk::<D>(&path, &format!("{}/", relative))?;
+                    continue;
+                }
+                None if metadata.is_dir() => {
+                    self.report.extra.push(format!("{}/", relative));
+                    continue;
+                }
+                None => {
+                    self.report.extra.push(relative);
+                    continue;
+                }
+            };
+
+            self.seen.insert(relative.clone());
+            let actual = if metadata.file_type().is_symlink() {
+                Some((Mode::Symlink, hash_link::<D>(&path)?))
+            } else if metadata.is_file() {
+                let mode = file_mode(&metadata, expected.0);
+                Some((mode, hash_file::<D>(&path, metadata.len())?))
+            } else {
+                None
+            };
+            if actual != Some(expected) {
+                self.report.modified.push(relative);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Compares the directory `root` against `version`, reporting files whose
+// contents or mode differ, files the version lists that are gone, and
+// anything present that the version does not list.
+pub fn verify<D, P>(version: &Version, root: P) -> anyhow::Result<Report>
+where
+    D: 'static + Digest + Send + Sync,
+    P: AsRef<Path>,
+{
+    let mut directories = HashSet::new();
+    for (path, _, _) in version.paths() {
+        let mut rest = path.as_str();
+        while let Some(idx) = rest.rfind('/') {
+            rest = &rest[..idx];
+            directories.insert(rest);
+        }
+    }
+
+    let mut walk = Walk {
+        expected: version
+            .paths()
+            .iter()
+            .map(|(path, mode, id)| (path.as_str(), (*mode, *id)))
+            .collect(),
+        directories,
+        seen: HashSet::new(),
+        report: Report::default(),
+    };
+    walk.walk::<D>(root.as_ref(), "")?;
+
+    for (path, _, _) in version.paths() {
+        if !walk.seen.contains(path) {
+            walk.report.missing.push(path.clone());
+        }
+    }
+    Ok(walk.report)
+}
+
+// Brings `root` back in line with `version` given the result of `verify`:
+// extra paths are removed, and modified and missing files are rewritten
+// from `store`. A report can come from anywhere, so every path in it is
+// checked before anything is touched: none may leave `root`, and modified
+// and missing paths must be entries of `version`.
+pub fn repair<R, P>(version: &Version, root: P, store: &R, report: &Report) -> anyhow::Result<()>
+where
+    R: ReadableStore,
+    P: AsRef<Path>,
+{
+    let root = root.as_ref();
+    version.check_paths()?;
+    let entries: HashMap<_, _> = version
+        .paths()
+        .iter()
+        .map(|(path, mode, id)| (path.as_str(), (*mode, id)))
+        .collect();
+    for path in report.extra.iter() {
+        check_path(path.strip_suffix('/').unwrap_or(path))?;
+    }
+    for path in report.modified.iter().chain(report.missing.iter()) {
+        if !entries.contains_key(path.as_str()) {
+            bail!("{} is not part of the version", path);
+        }
+    }
+
+    for path in report.extra.iter() {
+        match path.strip_suffix('/') {
+            Some(dir) => fs::remove_dir_all(root.join(dir))?,
+            None => fs::remove_file(root.join(path))?,
+        }
+    }
+
+    for path in report.modified.iter() {
+        let target = root.join(path);
+        if fs::symlink_metadata(&target)?.is_dir() {
+            fs::remove_dir_all(&target)?;
+        } else {
+            fs::remove_file(&target)?;
+        }
+    }
+
+    for path in report.modified.iter().chain(report.missing.iter()) {
+        let (mode, id) = entries[path.as_str()];
+        let bytes = match store.get_sync(id)? {
+            Some(Envelope::Blob(bytes)) => bytes,
+            Some(other) => bail!("expected a blob for {}, got a {}", path, other),
+            None => bail!("could not find the blob for {}", path),
+        };
+
+        let target = root.join(path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        install_sync(&target, path, mode, &bytes[..])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::version::VersionBuilder;
+    use crate::stores::WritableStore;
+    use crate::testing::{scratch_dir, scratch_store};
+    use sha2::Sha256;
+
+    #[async_std::test]
+    async fn verify_reports_and_repairs_changes() {
+        let store = scratch_store("verify-store");
+        let mut builder = VersionBuilder::new("verified", "1.0.0");
+        for (path, data) in &[
+            ("index.js", &b"index"[..]),
+            ("lib/a.js", &b"a"[..]),
+            ("lib/b.js", &b"b"[..]),
+        ] {
+            let blob = Envelope::Blob(data.to_vec());
+            let (id, _) = blob.content_address::<Sha256>();
+            store.add(blob).await.unwrap();
+            builder = builder.path(path, &id);
+        }
+        let version = builder.build();
+
+        let mut root = scratch_dir("verify-dest");
+        root.push("pkg");
+        version.unpack_sync(&root, &store).expect("failed to unpack");
+        assert!(verify::<Sha256, _>(&version, &root).unwrap().is_clean());
+
+        fs::write(root.join("index.js"), b"edited").unwrap();
+        fs::remove_file(root.join("lib/b.js")).unwrap();
+        fs::write(root.join("lib/c.js"), b"c").unwrap();
+        fs::create_dir_all(root.join("node_modules/dep")).unwrap();
+
+        let report = verify::<Sha256, _>(&version, &root).unwrap();
+        assert_eq!(
+            report,
+            Report {
+                modified: vec!["index.js".to_string()],
+                missing: vec!["lib/b.js".to_string()],
+                extra: vec!["lib/c.js".to_string(), "node_modules/".to_string()],
+            }
+        );
+
+        repair(&version, &root, &store, &report).expect("failed to repair");
+        assert!(verify::<Sha256, _>(&version, &root).unwrap().is_clean());
+        assert_eq!(fs::read(root.join("index.js")).unwrap(), b"index");
+
+        // Reports are not trusted to stay inside the directory.
+        let outside = root.parent().unwrap();
+        fs::write(outside.join("keep"), b"keep").unwrap();
+        for report in &[
+            Report {
+                extra: vec!["../keep".to_string()],
+                ..Report::default()
+            },
+            Report {
+                modified: vec!["../keep".to_string()],
+                ..Report::default()
+            },
+        ] {
+            assert!(repair(&version, &root, &store, report).is_err());
+        }
+        assert_eq!(fs::read(outside.join("keep")).unwrap(), b"keep");
+    }
+}